@@ -30,9 +30,148 @@ pub const REQUEST_SIZE: &str = concat!(env!("CARGO_CRATE_NAME"), "_", "request_s
 pub const RESPONSE_SIZE: &str = concat!(env!("CARGO_CRATE_NAME"), "_", "response_size_bytes");
 
 pub const TOKEN_SECONDS: &str = concat!(env!("CARGO_CRATE_NAME"), "_", "azure_api_token_update_duration_seconds");
+pub const CLIENT_CERTIFICATE_SECONDS: &str = concat!(env!("CARGO_CRATE_NAME"), "_", "azure_client_certificate_remaining_seconds");
 pub const APPLICATIONS_SECONDS: &str = concat!(env!("CARGO_CRATE_NAME"), "_", "azure_applications_update_duration_seconds");
+pub const APPLICATIONS_DELTA_CHANGES_TOTAL: &str = concat!(env!("CARGO_CRATE_NAME"), "_", "azure_applications_delta_changes_total");
+pub const SERVICE_PRINCIPALS_SECONDS: &str = concat!(env!("CARGO_CRATE_NAME"), "_", "azure_service_principals_update_duration_seconds");
+pub const APPLICATIONS_LAST_SUCCESS: &str = concat!(env!("CARGO_CRATE_NAME"), "_", "azure_applications_last_success_timestamp_seconds");
+pub const APPLICATIONS_CONSECUTIVE_FAILURES: &str = concat!(env!("CARGO_CRATE_NAME"), "_", "azure_applications_consecutive_failures");
+pub const SERVICE_PRINCIPALS_LAST_SUCCESS: &str = concat!(env!("CARGO_CRATE_NAME"), "_", "azure_service_principals_last_success_timestamp_seconds");
+pub const SERVICE_PRINCIPALS_CONSECUTIVE_FAILURES: &str = concat!(env!("CARGO_CRATE_NAME"), "_", "azure_service_principals_consecutive_failures");
+pub const AZURE_ERRORS_TOTAL: &str = concat!(env!("CARGO_CRATE_NAME"), "_", "azure_errors_total");
+pub const AZURE_DECODE_ERRORS_TOTAL: &str = concat!(env!("CARGO_CRATE_NAME"), "_", "azure_decode_errors_total");
+pub const AZURE_REQUEST_RETRIES_TOTAL: &str = concat!(env!("CARGO_CRATE_NAME"), "_", "azure_request_retries_total");
+pub const AZURE_THROTTLED_REQUESTS_TOTAL: &str = concat!(env!("CARGO_CRATE_NAME"), "_", "azure_throttled_requests_total");
 
 pub const APPLICATION_PASSWORD_SECONDS: &str = concat!(env!("CARGO_CRATE_NAME"), "_", "azure_application_password_remaining_seconds");
+pub const APPLICATION_CERTIFICATE_SECONDS: &str = concat!(env!("CARGO_CRATE_NAME"), "_", "azure_application_certificate_remaining_seconds");
+pub const SERVICE_PRINCIPAL_PASSWORD_SECONDS: &str = concat!(env!("CARGO_CRATE_NAME"), "_", "azure_service_principal_password_remaining_seconds");
+pub const SERVICE_PRINCIPAL_CERTIFICATE_SECONDS: &str =
+    concat!(env!("CARGO_CRATE_NAME"), "_", "azure_service_principal_certificate_remaining_seconds");
+pub const APPLICATION_PASSWORD_EXPIRY: &str = concat!(env!("CARGO_CRATE_NAME"), "_", "azure_application_password_expiry_timestamp_seconds");
+pub const APPLICATION_CERTIFICATE_EXPIRY: &str = concat!(env!("CARGO_CRATE_NAME"), "_", "azure_application_certificate_expiry_timestamp_seconds");
+pub const SERVICE_PRINCIPAL_PASSWORD_EXPIRY: &str =
+    concat!(env!("CARGO_CRATE_NAME"), "_", "azure_service_principal_password_expiry_timestamp_seconds");
+pub const SERVICE_PRINCIPAL_CERTIFICATE_EXPIRY: &str = concat!(
+    env!("CARGO_CRATE_NAME"),
+    "_",
+    "azure_service_principal_certificate_expiry_timestamp_seconds"
+);
+pub const CREDENTIAL_INFO: &str = concat!(env!("CARGO_CRATE_NAME"), "_", "azure_credential_info");
+pub const CREDENTIAL_AGE: &str = concat!(env!("CARGO_CRATE_NAME"), "_", "azure_credential_age_seconds");
+pub const CREDENTIAL_LIFETIME: &str = concat!(env!("CARGO_CRATE_NAME"), "_", "azure_credential_lifetime_seconds");
+pub const APPLICATION_AGE: &str = concat!(env!("CARGO_CRATE_NAME"), "_", "azure_application_age_seconds");
+pub const POLICY_VIOLATION: &str = concat!(env!("CARGO_CRATE_NAME"), "_", "azure_policy_violation");
+pub const CREDENTIALS_EXPIRED: &str = concat!(env!("CARGO_CRATE_NAME"), "_", "azure_credentials_expired");
+pub const CREDENTIALS_EXPIRING: &str = concat!(env!("CARGO_CRATE_NAME"), "_", "azure_credentials_expiring");
+pub const CREDENTIALS_WITHOUT_END_DATE: &str = concat!(env!("CARGO_CRATE_NAME"), "_", "azure_credentials_without_end_date");
+pub const CREDENTIALS_UNDECODABLE: &str = concat!(env!("CARGO_CRATE_NAME"), "_", "azure_credentials_undecodable");
+pub const APPLICATIONS_WITHOUT_CREDENTIALS: &str = concat!(env!("CARGO_CRATE_NAME"), "_", "azure_applications_without_credentials");
+
+/// The credential gauges and their descriptions, which are either set by the metrics updater or computed by `/metrics` on scrape
+pub const CREDENTIAL_METRICS: &[(&str, &str)] = &[
+    (
+        CLIENT_CERTIFICATE_SECONDS,
+        "Seconds remaining until the certificate the exporter authenticates to Azure with expires.",
+    ),
+    (APPLICATION_PASSWORD_SECONDS, "Seconds remaining until the password credential expires."),
+    (
+        APPLICATION_CERTIFICATE_SECONDS,
+        "Seconds remaining until the certificate (key credential) expires.",
+    ),
+    (
+        SERVICE_PRINCIPAL_PASSWORD_SECONDS,
+        "Seconds remaining until the service principal password credential expires.",
+    ),
+    (
+        SERVICE_PRINCIPAL_CERTIFICATE_SECONDS,
+        "Seconds remaining until the service principal certificate (key credential) expires, including SAML token signing certificates.",
+    ),
+    (APPLICATION_PASSWORD_EXPIRY, "Unix timestamp of when the password credential expires."),
+    (
+        APPLICATION_CERTIFICATE_EXPIRY,
+        "Unix timestamp of when the certificate (key credential) expires.",
+    ),
+    (
+        SERVICE_PRINCIPAL_PASSWORD_EXPIRY,
+        "Unix timestamp of when the service principal password credential expires.",
+    ),
+    (
+        SERVICE_PRINCIPAL_CERTIFICATE_EXPIRY,
+        "Unix timestamp of when the service principal certificate (key credential) expires.",
+    ),
+    (
+        CREDENTIAL_INFO,
+        "Descriptive labels of each password and certificate credential of the applications and service principals, always 1.",
+    ),
+    (
+        CREDENTIAL_AGE,
+        "Seconds since the start date of each password and certificate credential of the applications and service principals.",
+    ),
+    (
+        CREDENTIAL_LIFETIME,
+        "Seconds between the start and end date of each password and certificate credential of the applications and service principals.",
+    ),
+    (APPLICATION_AGE, "Seconds since the application was created."),
+    (
+        POLICY_VIOLATION,
+        "1 if the application violates a rule of the policy, 0 if it complies. See /api/policies/violations for the reasons.",
+    ),
+    (
+        CREDENTIALS_EXPIRED,
+        "Number of password and certificate credentials whose end date has passed.",
+    ),
+    (
+        CREDENTIALS_EXPIRING,
+        "Number of password and certificate credentials that have not expired yet and expire within the window.",
+    ),
+    (
+        CREDENTIALS_WITHOUT_END_DATE,
+        "Number of password and certificate credentials without an end date.",
+    ),
+    (
+        CREDENTIALS_UNDECODABLE,
+        "Number of password and certificate credentials that failed to decode, which have no other credential metrics.",
+    ),
+    (
+        APPLICATIONS_WITHOUT_CREDENTIALS,
+        "Number of applications without any password or certificate credentials.",
+    ),
+];
+
+/// All labels of the credential metrics, which the `[metrics.labels]` settings select from
+pub const CREDENTIAL_LABELS: &[&str] = &[
+    "tenant_id",
+    "id",
+    "app_id",
+    "app_display_name",
+    "service_principal_display_name",
+    "service_principal_type",
+    "object",
+    "credential_type",
+    "password_key_id",
+    "password_display_name",
+    "password_end_date_time",
+    "certificate_key_id",
+    "certificate_display_name",
+    "certificate_type",
+    "certificate_usage",
+    "certificate_thumbprint",
+    "certificate_end_date_time",
+    "certificate_preferred_token_signing",
+];
+
+/// All labels the summary gauges can be partitioned by, which the `[metrics.labels]` settings select from
+pub const SUMMARY_LABELS: &[&str] = &["tenant_id", "object", "credential_type"];
+
+pub const BACKGROUND_TASK_UP: &str = concat!(env!("CARGO_CRATE_NAME"), "_", "background_task_up");
+pub const BACKGROUND_TASK_RESTARTS_TOTAL: &str = concat!(env!("CARGO_CRATE_NAME"), "_", "background_task_restarts_total");
+
+pub const SETTINGS_RELOADS_TOTAL: &str = concat!(env!("CARGO_CRATE_NAME"), "_", "settings_reloads_total");
+pub const SETTINGS_LAST_RELOAD_SUCCESSFUL: &str = concat!(env!("CARGO_CRATE_NAME"), "_", "settings_last_reload_successful");
+pub const SETTINGS_LAST_RELOAD_SUCCESS: &str = concat!(env!("CARGO_CRATE_NAME"), "_", "settings_last_reload_success_timestamp_seconds");
+pub const TLS_CERTIFICATE_EXPIRY: &str = concat!(env!("CARGO_CRATE_NAME"), "_", "tls_certificate_expiry_timestamp_seconds");
+pub const TLS_REJECTED_HANDSHAKES_TOTAL: &str = concat!(env!("CARGO_CRATE_NAME"), "_", "tls_rejected_handshakes_total");
 
 const APP_INFO: &str = concat!(env!("CARGO_CRATE_NAME"), "_", "app_info");
 const RUST_INFO: &str = concat!(env!("CARGO_CRATE_NAME"), "_", "rust_info");
@@ -53,10 +192,84 @@ pub fn setup_metrics() {
 
     describe_histogram!(
         APPLICATIONS_SECONDS,
-        "How many seconds it takes to update the in-memory cache of Azure applications."
+        "How many seconds it takes to update the in-memory cache of Azure applications, partitioned by full or delta sync."
+    );
+    describe_counter!(
+        APPLICATIONS_DELTA_CHANGES_TOTAL,
+        "Number of changed Azure applications received from delta queries, partitioned by upserted or removed."
+    );
+
+    describe_histogram!(
+        SERVICE_PRINCIPALS_SECONDS,
+        "How many seconds it takes to update the in-memory cache of Azure service principals."
+    );
+
+    describe_gauge!(
+        APPLICATIONS_LAST_SUCCESS,
+        "Unix timestamp of the last successful update of the in-memory cache of Azure applications."
+    );
+    describe_gauge!(
+        APPLICATIONS_CONSECUTIVE_FAILURES,
+        "Number of failed updates of the in-memory cache of Azure applications since the last successful one."
+    );
+    describe_gauge!(
+        SERVICE_PRINCIPALS_LAST_SUCCESS,
+        "Unix timestamp of the last successful update of the in-memory cache of Azure service principals."
+    );
+    describe_gauge!(
+        SERVICE_PRINCIPALS_CONSECUTIVE_FAILURES,
+        "Number of failed updates of the in-memory cache of Azure service principals since the last successful one."
+    );
+
+    describe_counter!(
+        AZURE_ERRORS_TOTAL,
+        "Number of failed requests to Azure, partitioned by kind of error and endpoint."
+    );
+    describe_counter!(
+        AZURE_DECODE_ERRORS_TOTAL,
+        "Number of Azure objects and credentials that failed to decode, partitioned by object type."
+    );
+    describe_counter!(
+        AZURE_REQUEST_RETRIES_TOTAL,
+        "Number of retried requests to Azure, partitioned by endpoint and reason (throttled, server_error or network)."
+    );
+    describe_counter!(
+        AZURE_THROTTLED_REQUESTS_TOTAL,
+        "Number of requests to Azure that were throttled with a 429 or 503 status, partitioned by endpoint."
+    );
+
+    for (name, description) in CREDENTIAL_METRICS {
+        describe_gauge!(*name, *description);
+    }
+
+    describe_gauge!(
+        BACKGROUND_TASK_UP,
+        "Whether the background task is running (1) or crashed and waiting to be restarted (0)."
+    );
+    describe_counter!(
+        BACKGROUND_TASK_RESTARTS_TOTAL,
+        "Number of times the background task crashed and was restarted."
+    );
+
+    describe_counter!(SETTINGS_RELOADS_TOTAL, "Number of settings reloads, partitioned by trigger and result.");
+    describe_gauge!(
+        SETTINGS_LAST_RELOAD_SUCCESSFUL,
+        "Whether the last settings reload was successful (1) or the settings file was rejected (0)."
+    );
+    describe_gauge!(
+        SETTINGS_LAST_RELOAD_SUCCESS,
+        "Unix timestamp of the last successful settings reload, or of the startup if the settings were not reloaded yet."
     );
 
-    describe_gauge!(APPLICATION_PASSWORD_SECONDS, "Seconds remaining until the password credential expires.");
+    describe_gauge!(
+        TLS_CERTIFICATE_EXPIRY,
+        "Unix timestamp of the notAfter date of the certificate a listener of the exporter serves HTTPS with."
+    );
+
+    describe_counter!(
+        TLS_REJECTED_HANDSHAKES_TOTAL,
+        "Number of TLS handshakes rejected because the client certificate is missing, invalid or not allowed."
+    );
 
     counter!(APP_INFO, &[("version", env!("CARGO_PKG_VERSION"))]).increment(1);
 
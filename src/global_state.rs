@@ -17,11 +17,23 @@
  * under the License.
  */
 
-use std::{collections::HashMap, sync::RwLock, time::Duration};
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+    time::Duration,
+};
+
+use axum_server::tls_rustls::RustlsConfig;
+use chrono::{DateTime, Utc};
+use ring::{hmac, rand::SystemRandom};
 
 use crate::{
-    settings::app_settings::{self, Settings},
-    types::applications::AzureApplication,
+    settings::{
+        app_settings::{self, Settings, Tenant},
+        listen_address::ListenAddress,
+    },
+    types::{applications::AzureApplication, lenient::DecodeFailure, policies::PolicyViolation, service_principals::AzureServicePrincipal},
+    utils::{self, ClientCertificate, RwLock, ServingCertificate},
 };
 
 /// Struct containing all the data we want to easily access and mutate throughout the project.
@@ -33,17 +45,126 @@ use crate::{
 /// Once the program terminates the struct isn't freed since we leaked it,
 /// but any self-respecting OS automatically reclaims unfreed memory after a process terminates so all is good.
 pub struct GlobalState {
-    pub settings: Settings,
+    /// Replaced on every settings reload, use [`GlobalState::settings`] to get the current settings
+    settings: RwLock<Arc<Settings>>,
+    pub settings_path: String,
+    pub settings_reload_status: RwLock<ReloadStatus>,
+    /// One entry for each listener in the settings, in the same order
+    pub listeners: Vec<ListenerState>,
     pub http_client: reqwest::Client,
+    /// One entry for each tenant in the settings, in the same order
+    pub tenants: Vec<TenantState>,
+    pub background_tasks: RwLock<Vec<BackgroundTask>>,
+    /// HMACs of basic auth credentials which were verified against their password hash before,
+    /// so that scrapes don't pay for a bcrypt or argon2 verification every time
+    pub verified_passwords: RwLock<HashSet<Vec<u8>>>,
+    /// Random key of the [`GlobalState::verified_passwords`] HMACs, generated on every start so the cached entries
+    /// can't be brute-forced like unsalted digests of the passwords
+    pub verified_passwords_key: hmac::Key,
+    /// `metrics.compute_on_scrape` at startup. Not reloaded, as the gauges set before switching would be exported until pruned
+    pub compute_metrics_on_scrape: bool,
+}
+
+/// A supervised task updating the caches or metrics
+pub struct BackgroundTask {
+    pub name: String,
+    pub state: TaskState,
+    pub restarts: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TaskState {
+    Running,
+    /// The task crashed and is waiting to be restarted
+    Restarting,
+}
+
+impl TaskState {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TaskState::Running => "running",
+            TaskState::Restarting => "restarting",
+        }
+    }
+}
+
+/// The TLS config of a listener the server is bound to
+pub struct ListenerState {
+    pub listen_address: ListenAddress,
+    /// The TLS config of the listener, or `None` if serving plain HTTP. Rebuilt on every settings reload
+    pub tls_config: Option<RustlsConfig>,
+    pub serving_certificate: RwLock<Option<ServingCertificate>>,
+}
+
+/// The API token and cached Azure objects of a single tenant
+pub struct TenantState {
+    pub tenant_id: String,
     /// HashMap of id -> application
     pub applications: RwLock<HashMap<String, AzureApplication>>,
+    pub applications_status: RwLock<CacheStatus>,
+    /// Applications that failed to decode and are therefore missing from the cache
+    pub application_decode_failures: RwLock<Vec<DecodeFailure>>,
+    /// Link to request the applications changed since the last refresh, if delta queries are enabled
+    pub applications_delta_link: RwLock<Option<String>>,
+    /// Violations of the `[[policies]]` settings, evaluated after each refresh of the applications cache
+    pub policy_violations: RwLock<Vec<PolicyViolation>>,
+    /// HashMap of id -> service principal
+    pub service_principals: RwLock<HashMap<String, AzureServicePrincipal>>,
+    pub service_principals_status: RwLock<CacheStatus>,
+    /// Service principals that failed to decode and are therefore missing from the cache
+    pub service_principal_decode_failures: RwLock<Vec<DecodeFailure>>,
     pub azure_api_token: RwLock<String>,
+    /// The certificate used to get the API token, if authenticating with a certificate instead of a client secret
+    pub client_certificate: RwLock<Option<ClientCertificate>>,
+    /// Notified when a settings reload changed the credentials of the tenant, to get a new API token right away
+    pub credentials_changed: tokio::sync::Notify,
+}
+
+/// Outcome of the last settings reload
+#[derive(Debug, Clone)]
+pub struct ReloadStatus {
+    /// When the settings were last loaded successfully, either at startup or on a reload
+    pub last_success: DateTime<Utc>,
+    pub successful: bool,
 }
 
 impl GlobalState {
-    #[allow(clippy::new_without_default)]
-    pub fn new() -> Self {
-        let settings = app_settings::parse();
+    /// Fails if the settings are invalid, or the TLS certificate or key of a listener cannot be loaded
+    pub fn new() -> Result<Self, String> {
+        let settings_path = app_settings::settings_path();
+        let settings = app_settings::parse(&settings_path)?;
+
+        Self::from_settings(settings_path, settings)
+    }
+
+    /// State for tests, with the settings parsed from a string instead of a file
+    #[cfg(test)]
+    pub fn from_toml(settings_contents: &str) -> Self {
+        let settings = app_settings::parse_contents("settings.toml", settings_contents).expect("test settings must be valid");
+        Self::from_settings("settings.toml".into(), settings).expect("test settings must be valid")
+    }
+
+    /// Fails if the TLS certificate or key of a listener cannot be loaded
+    pub fn from_settings(settings_path: String, settings: Settings) -> Result<Self, String> {
+        let listeners = settings
+            .web
+            .listeners()
+            .iter()
+            .map(|listener| {
+                let (tls_config, serving_certificate) = match utils::tls_config_from_settings(listener, &settings).transpose()? {
+                    Some((server_config, serving_certificate)) => {
+                        (Some(RustlsConfig::from_config(Arc::new(server_config))), Some(serving_certificate))
+                    }
+                    None => (None, None),
+                };
+
+                Ok(ListenerState {
+                    listen_address: listener.listen_address.clone(),
+                    tls_config,
+                    serving_certificate: RwLock::new(serving_certificate),
+                })
+            })
+            .collect::<Result<_, String>>()?;
 
         let http_client = reqwest::ClientBuilder::new()
             .danger_accept_invalid_certs(settings.debug.no_verify_tls)
@@ -52,11 +173,197 @@ impl GlobalState {
             .build()
             .expect("must create http client");
 
-        Self {
-            settings,
+        let tenants = settings
+            .tenants
+            .iter()
+            .map(|tenant| TenantState {
+                tenant_id: tenant.credentials.tenant_id.clone(),
+                applications: RwLock::default(),
+                applications_status: RwLock::default(),
+                application_decode_failures: RwLock::default(),
+                applications_delta_link: RwLock::default(),
+                policy_violations: RwLock::default(),
+                service_principals: RwLock::default(),
+                service_principals_status: RwLock::default(),
+                service_principal_decode_failures: RwLock::default(),
+                azure_api_token: RwLock::default(),
+                client_certificate: RwLock::default(),
+                credentials_changed: tokio::sync::Notify::new(),
+            })
+            .collect();
+
+        let compute_metrics_on_scrape = settings.metrics.compute_on_scrape;
+
+        let verified_passwords_key =
+            hmac::Key::generate(hmac::HMAC_SHA256, &SystemRandom::new()).map_err(|_| "failed generating the password cache key".to_string())?;
+
+        Ok(Self {
+            settings: RwLock::new(Arc::new(settings)),
+            settings_path,
+            settings_reload_status: RwLock::new(ReloadStatus {
+                last_success: Utc::now(),
+                successful: true,
+            }),
+            listeners,
             http_client,
-            applications: RwLock::default(),
-            azure_api_token: RwLock::default(),
+            tenants,
+            background_tasks: RwLock::default(),
+            verified_passwords: RwLock::default(),
+            verified_passwords_key,
+            compute_metrics_on_scrape,
+        })
+    }
+
+    pub fn tenant(&self, tenant_id: &str) -> Option<&TenantState> {
+        self.tenants.iter().find(|tenant| tenant.tenant_id == tenant_id)
+    }
+
+    /// Get the current settings. Long-running tasks should get them again on every iteration to pick up reloads
+    pub fn settings(&self) -> Arc<Settings> {
+        self.settings.read().clone()
+    }
+
+    /// Replace the settings with reloaded ones
+    pub fn set_settings(&self, settings: Settings) {
+        *self.settings.write() = Arc::new(settings);
+    }
+
+    /// Whether the caches of all tenants finished a refresh since startup, successfully or not
+    pub fn first_attempt_completed(&self) -> bool {
+        self.tenants.iter().all(|tenant| {
+            let tenant_settings = self.tenant_settings(tenant);
+
+            (!tenant_settings.applications.enabled || tenant.applications_status.read().first_attempt_completed())
+                && (!tenant_settings.service_principals.enabled || tenant.service_principals_status.read().first_attempt_completed())
+        })
+    }
+
+    /// Whether none of the background tasks crashed and is waiting to be restarted
+    pub fn background_tasks_running(&self) -> bool {
+        self.background_tasks.read().iter().all(|task| task.state == TaskState::Running)
+    }
+
+    /// Get the current settings of a tenant
+    pub fn tenant_settings(&self, tenant: &TenantState) -> Tenant {
+        self.settings()
+            .tenant(&tenant.tenant_id)
+            .expect("tenant states are created from the tenants in the settings, and reloads cannot add or remove tenants")
+            .clone()
+    }
+}
+
+/// Freshness of a cache of Azure objects. If a refresh fails, the cache keeps the last known good data and is marked as stale
+#[derive(Debug, Clone)]
+pub struct CacheStatus {
+    pub last_success: Option<DateTime<Utc>>,
+    pub consecutive_failures: u64,
+    /// Whether the last refresh failed or there was no successful refresh yet
+    pub stale: bool,
+}
+
+impl Default for CacheStatus {
+    fn default() -> Self {
+        Self {
+            last_success: None,
+            consecutive_failures: 0,
+            stale: true,
+        }
+    }
+}
+
+impl CacheStatus {
+    pub fn record_success(&mut self) {
+        self.last_success = Some(Utc::now());
+        self.consecutive_failures = 0;
+        self.stale = false;
+    }
+
+    pub fn record_failure(&mut self) {
+        self.consecutive_failures += 1;
+        self.stale = true;
+    }
+
+    /// Whether a refresh has finished since startup, successfully or not
+    pub fn first_attempt_completed(&self) -> bool {
+        self.last_success.is_some() || self.consecutive_failures > 0
+    }
+
+    /// Combine the statuses of multiple caches, e.g. of all tenants, into the status of the least fresh one
+    pub fn combine(statuses: impl IntoIterator<Item = CacheStatus>) -> Self {
+        statuses
+            .into_iter()
+            .reduce(|a, b| Self {
+                last_success: a.last_success.zip(b.last_success).map(|(a, b)| a.min(b)),
+                consecutive_failures: a.consecutive_failures.max(b.consecutive_failures),
+                stale: a.stale || b.stale,
+            })
+            .unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn status(last_success: Option<&str>, consecutive_failures: u64, stale: bool) -> CacheStatus {
+        CacheStatus {
+            last_success: last_success.map(|date| date.parse().unwrap()),
+            consecutive_failures,
+            stale,
         }
     }
+
+    #[test]
+    fn combined_status_is_the_least_fresh_one() {
+        let combined = CacheStatus::combine([
+            status(Some("2024-01-01T00:10:00Z"), 0, false),
+            status(Some("2024-01-01T00:05:00Z"), 2, true),
+            status(Some("2024-01-01T00:15:00Z"), 1, false),
+        ]);
+
+        assert_eq!(combined.last_success, Some("2024-01-01T00:05:00Z".parse().unwrap()));
+        assert_eq!(combined.consecutive_failures, 2);
+        assert!(combined.stale);
+    }
+
+    #[test]
+    fn combined_status_without_success_if_one_cache_never_succeeded() {
+        let combined = CacheStatus::combine([status(Some("2024-01-01T00:10:00Z"), 0, false), status(None, 3, true)]);
+
+        assert_eq!(combined.last_success, None);
+        assert_eq!(combined.consecutive_failures, 3);
+        assert!(combined.stale);
+        assert!(combined.first_attempt_completed());
+    }
+
+    #[test]
+    fn combined_status_of_no_caches_is_stale() {
+        let combined = CacheStatus::combine([]);
+
+        assert_eq!(combined.last_success, None);
+        assert_eq!(combined.consecutive_failures, 0);
+        assert!(combined.stale);
+        assert!(!combined.first_attempt_completed());
+    }
+
+    #[test]
+    fn first_attempt_completes_with_failed_refreshes() {
+        let global_state = GlobalState::from_toml(
+            "[[tenants]]\ntenant_id = \"t1\"\nclient_id = \"c1\"\ntoken_source = \"managed_identity\"\n[tenants.service_principals]\nenabled = true\n\
+             [[tenants]]\ntenant_id = \"t2\"\nclient_id = \"c2\"\ntoken_source = \"managed_identity\"\n",
+        );
+        let [t1, t2] = &global_state.tenants[..] else {
+            panic!("expected 2 tenants")
+        };
+
+        assert!(!global_state.first_attempt_completed());
+
+        t1.applications_status.write().record_success();
+        t2.applications_status.write().record_failure();
+        assert!(!global_state.first_attempt_completed());
+
+        // The service principals of t2 are not enabled, so they are not waited for
+        t1.service_principals_status.write().record_failure();
+        assert!(global_state.first_attempt_completed());
+    }
 }
@@ -17,31 +17,80 @@
  * under the License.
  */
 
-use std::{path::Path, sync::Arc};
+use std::time::Duration;
 
-use axum::{response::Redirect, routing::get, Extension, Router};
-use axum_server::tls_rustls::RustlsConfig;
-use metrics_exporter_prometheus::Matcher;
+use axum::{
+    response::Redirect,
+    routing::{get, post},
+    Extension, Router,
+};
+use axum_server::Handle;
+use metrics_exporter_prometheus::{Matcher, PrometheusHandle};
 use metrics_util::MetricKindMask;
-use utoipa::OpenApi;
+use utoipa::{
+    openapi::security::{Http, HttpAuthScheme, SecurityScheme},
+    Modify, OpenApi,
+};
 use utoipa_swagger_ui::{Config, SwaggerUi};
 
 use azure_app_exporter::{
     app_metrics,
-    global_state::GlobalState,
+    global_state::{GlobalState, ListenerState},
     middleware, routes,
-    settings::{app_settings, args},
-    tasks, types, utils,
+    settings::{
+        app_settings::{self, Scope},
+        args,
+    },
+    tasks, types,
+    utils::{self, ListenSocket},
 };
 
 #[derive(OpenApi)]
 #[openapi(
     info(title = "Azure app exporter", contact()),
-    paths(routes::metrics, routes::show_settings, routes::get_all_applications, routes::get_application_by_id),
-    components(schemas(app_settings::Settings, types::applications::AzureApplication))
+    modifiers(&SecurityAddon),
+    security(("basic_auth" = []), ("bearer_auth" = [])),
+    paths(
+        routes::metrics,
+        routes::healthz,
+        routes::readyz,
+        routes::show_settings,
+        routes::reload,
+        routes::get_all_applications,
+        routes::get_application_by_id,
+        routes::get_tenant_applications,
+        routes::get_tenant_application_by_id,
+        routes::get_all_service_principals,
+        routes::get_service_principal_by_id,
+        routes::get_tenant_service_principals,
+        routes::get_tenant_service_principal_by_id,
+        routes::get_decode_errors,
+        routes::get_policy_violations
+    ),
+    components(schemas(
+        app_settings::Settings,
+        types::applications::AzureApplication,
+        types::service_principals::AzureServicePrincipal,
+        types::policies::PolicyViolation,
+        routes::DecodeError,
+        routes::HealthReport,
+        routes::ReloadResult
+    ))
 )]
 struct ApiDoc;
 
+/// Lets Swagger UI send the credentials of the `[auth]` settings. Both schemes are documented even if authentication is disabled
+struct SecurityAddon;
+
+impl Modify for SecurityAddon {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        let components = openapi.components.get_or_insert_with(Default::default);
+
+        components.add_security_scheme("basic_auth", SecurityScheme::Http(Http::new(HttpAuthScheme::Basic)));
+        components.add_security_scheme("bearer_auth", SecurityScheme::Http(Http::new(HttpAuthScheme::Bearer)));
+    }
+}
+
 #[tokio::main]
 async fn main() {
     args::check_args();
@@ -61,15 +110,24 @@ async fn main() {
 
     // We leak this because it needs to live as long as the application, be shared between threads
     // and because it's much easier to implicitly Copy a reference than explicitly Clone an Arc
-    let global_state = &*Box::leak(Box::new(GlobalState::new()));
+    let global_state = match GlobalState::new() {
+        Ok(global_state) => &*Box::leak(Box::new(global_state)),
+        Err(e) => {
+            tracing::error!(error = e, "failed starting exporter");
+            std::process::exit(1);
+        }
+    };
 
-    if global_state.settings.debug.no_verify_tls {
+    // Only used for what is set up once at startup, everything else gets the current settings to pick up reloads
+    let settings = global_state.settings();
+
+    if settings.debug.no_verify_tls {
         tracing::warn!("flag no_verify_tls is enabled, CERTIFICATES ON FOREIGN API REQUESTS WILL NOT BE VALIDATED!")
     }
 
     let metric_handle = metrics_exporter_prometheus::PrometheusBuilder::new()
         // Remove gauge metrics that have not been updated for the given span of time
-        .idle_timeout(MetricKindMask::GAUGE, global_state.settings.metrics.prune_interval)
+        .idle_timeout(MetricKindMask::GAUGE, settings.metrics.prune_interval)
         .set_buckets_for_metric(
             // Required to use real Prometheus histograms over summaries
             Matcher::Suffix("_duration_seconds".into()),
@@ -104,85 +162,207 @@ async fn main() {
 
     app_metrics::setup_metrics();
 
-    let router = if global_state.settings.openapi.enabled {
-        Router::new()
+    // Leaked for the same reason as the global state, the routes need it for as long as the application lives
+    let swagger_ui_url: &'static str = settings.openapi.swagger_ui_url.clone().leak();
+
+    let sockets = match utils::bind_listeners(&settings.web.listeners()) {
+        Ok(sockets) => sockets,
+        Err(e) => {
+            tracing::error!(error = e, "failed starting exporter");
+            std::process::exit(1);
+        }
+    };
+
+    for tenant in global_state.tenants.iter() {
+        let tenant_settings = global_state.tenant_settings(tenant);
+
+        if tenant_settings.applications.enabled || tenant_settings.service_principals.enabled {
+            tasks::spawn_supervised(global_state, format!("api_token_updater/{}", tenant.tenant_id), move || {
+                tasks::azure_api_token_updater(global_state, tenant)
+            });
+        }
+
+        if tenant_settings.applications.enabled {
+            tasks::spawn_supervised(global_state, format!("applications_updater/{}", tenant.tenant_id), move || {
+                tasks::azure_applications_updater(global_state, tenant)
+            });
+        }
+
+        if tenant_settings.service_principals.enabled {
+            tasks::spawn_supervised(global_state, format!("service_principals_updater/{}", tenant.tenant_id), move || {
+                tasks::azure_service_principals_updater(global_state, tenant)
+            });
+        }
+    }
+
+    if settings
+        .tenants
+        .iter()
+        .any(|tenant| tenant.applications.enabled || tenant.service_principals.enabled)
+    {
+        tasks::spawn_supervised(global_state, "metrics_updater".into(), move || tasks::azure_metrics_updater(global_state));
+    }
+
+    tasks::spawn_supervised(global_state, "settings_reloader".into(), move || tasks::settings_reloader(global_state));
+
+    if std::env::var_os("NOTIFY_SOCKET").is_some() {
+        tasks::spawn_supervised(global_state, "systemd_notifier".into(), move || tasks::systemd_notifier(global_state));
+    }
+
+    // Shared by the servers of all listeners, so a shutdown signal drains all of them. The handle drains the TCP listeners,
+    // and the receivers of the channel the Unix domain socket listeners
+    let handle = Handle::new();
+    let (shutdown_sender, shutdown_receiver) = tokio::sync::watch::channel(None);
+    tokio::spawn(shutdown_on_signal(handle.clone(), shutdown_sender, global_state));
+
+    let mut servers = tokio::task::JoinSet::new();
+
+    for ((listener, listener_state), socket) in settings.web.listeners().into_iter().zip(global_state.listeners.iter()).zip(sockets) {
+        let endpoints = listener.endpoints.iter().map(Scope::as_str).collect::<Vec<_>>().join(", ");
+        tracing::info!("beginning to serve {endpoints} on {}", listener.listen_address);
+
+        if listener.endpoints.contains(&Scope::Metrics) {
+            tracing::info!("metrics endpoint: {}/metrics", listener.listen_address);
+        }
+        if listener.endpoints.contains(&Scope::Api) && settings.openapi.enabled {
+            tracing::info!("swagger endpoint: {}{}", listener.listen_address, settings.openapi.swagger_ui_url);
+        }
+
+        let router = router(global_state, &listener.endpoints, swagger_ui_url, metric_handle.clone());
+        servers.spawn(serve(listener_state, socket, router, handle.clone(), shutdown_receiver.clone()));
+    }
+
+    while let Some(result) = servers.join_next().await {
+        result.expect("server panicked");
+    }
+
+    tracing::info!("server stopped, exiting");
+}
+
+/// The routes of a listener. Every listener serves the health checks, and the OpenAPI docs are served along with the API
+fn router(global_state: &'static GlobalState, endpoints: &[Scope], swagger_ui_url: &'static str, metric_handle: PrometheusHandle) -> Router {
+    let settings = global_state.settings();
+
+    // The health checks and the OpenAPI docs are always public, every other group of routes requires its own scope if authentication is enabled
+    let require_scope = |scope| axum::middleware::from_fn_with_state((global_state, scope), middleware::require_scope);
+
+    let mut router = Router::new()
+        .route("/healthz", get(routes::healthz))
+        .route("/readyz", get(routes::readyz));
+
+    if endpoints.contains(&Scope::Api) && settings.openapi.enabled {
+        router = router
             .merge(
-                SwaggerUi::new(&global_state.settings.openapi.swagger_ui_url)
-                    .url(global_state.settings.openapi.docs_url.clone(), ApiDoc::openapi())
+                SwaggerUi::new(swagger_ui_url)
+                    .url(settings.openapi.docs_url.clone(), ApiDoc::openapi())
                     .config(Config::default().use_base_layout().display_request_duration(true)),
             )
-            .route("/", get(|| async { Redirect::to(&global_state.settings.openapi.swagger_ui_url) }))
-    } else {
-        Router::new()
+            .route("/", get(|| async { Redirect::to(swagger_ui_url) }));
     }
-    .route("/metrics", get(routes::metrics))
-    .route("/api/settings", get(routes::show_settings))
-    .route("/api/apps", get(routes::get_all_applications))
-    .route("/api/apps/:id", get(routes::get_application_by_id))
-    .with_state(global_state)
-    .layer(Extension(metric_handle))
-    .layer(axum::middleware::map_request(|request| {
-        utils::set_swagger_ui_header(&global_state.settings.openapi.swagger_ui_url, request)
-    }))
-    .layer(axum::middleware::from_fn(middleware::logging));
-
-    tracing::info!("beginning to serve on {}", global_state.settings.web.listen_address);
-    tracing::info!("metrics endpoint: {}/metrics", global_state.settings.web.listen_address);
-    tracing::info!(
-        "swagger endpoint: {}{}",
-        global_state.settings.web.listen_address,
-        global_state.settings.openapi.swagger_ui_url
-    );
 
-    if global_state.settings.applications.enabled {
-        tokio::spawn(tasks::azure_api_token_updater(global_state));
-        tokio::spawn(tasks::azure_applications_updater(global_state));
-        tokio::spawn(tasks::azure_metrics_updater(global_state));
+    if endpoints.contains(&Scope::Metrics) {
+        router = router.merge(
+            Router::new()
+                .route("/metrics", get(routes::metrics))
+                .route_layer(require_scope(Scope::Metrics)),
+        );
     }
 
-    if let (Some(cert_path), Some(key_path)) = (&global_state.settings.web.cert_file, &global_state.settings.web.key_file) {
-        let tls_config = build_tls_config(cert_path, key_path, &global_state.settings.tls);
-
-        axum_server::bind_rustls(global_state.settings.web.listen_address, tls_config)
-            .serve(router.into_make_service())
-            .await
-            .expect("failed starting server");
-    } else {
-        tracing::warn!("no cert or key file provided in settings.toml, running server in HTTP mode");
-        axum_server::bind(global_state.settings.web.listen_address)
-            .serve(router.into_make_service())
-            .await
-            .expect("failed starting server");
+    if endpoints.contains(&Scope::Admin) {
+        router = router.merge(
+            Router::new()
+                .route("/api/settings", get(routes::show_settings))
+                .route("/-/reload", post(routes::reload))
+                .route_layer(require_scope(Scope::Admin)),
+        );
     }
+
+    if endpoints.contains(&Scope::Api) {
+        router = router.merge(
+            Router::new()
+                .route("/api/apps", get(routes::get_all_applications))
+                .route("/api/apps/:id", get(routes::get_application_by_id))
+                .route("/api/service-principals", get(routes::get_all_service_principals))
+                .route("/api/service-principals/:id", get(routes::get_service_principal_by_id))
+                .route("/api/tenants/:tenant/apps", get(routes::get_tenant_applications))
+                .route("/api/tenants/:tenant/apps/:id", get(routes::get_tenant_application_by_id))
+                .route("/api/tenants/:tenant/service-principals", get(routes::get_tenant_service_principals))
+                .route(
+                    "/api/tenants/:tenant/service-principals/:id",
+                    get(routes::get_tenant_service_principal_by_id),
+                )
+                .route("/api/diagnostics/decode-errors", get(routes::get_decode_errors))
+                .route("/api/policies/violations", get(routes::get_policy_violations))
+                .route_layer(require_scope(Scope::Api)),
+        );
+    }
+
+    router
+        .with_state(global_state)
+        .layer(Extension(metric_handle))
+        .layer(axum::middleware::map_request(|request| {
+            utils::set_swagger_ui_header(swagger_ui_url, request)
+        }))
+        .layer(axum::middleware::from_fn(middleware::logging))
 }
 
-// If we want to select which TLS ciphers and protocols we want to use, we'll have to build the TLS config a bit more manually
-fn build_tls_config(cert_path: &Path, key_path: &Path, tls_settings: &app_settings::Tls) -> RustlsConfig {
-    let mut cert_reader = std::io::BufReader::new(std::fs::File::open(cert_path).expect("tls cert path must be a file"));
-    let mut key_reader = std::io::BufReader::new(std::fs::File::open(key_path).expect("tls key path must be a file"));
-
-    let tls_certs = rustls_pemfile::certs(&mut cert_reader)
-        .flatten()
-        .map(|c| rustls::Certificate(c.to_vec()))
-        .collect::<Vec<_>>();
-
-    let tls_key = rustls_pemfile::pkcs8_private_keys(&mut key_reader)
-        .flatten()
-        .map(|k| rustls::PrivateKey(k.secret_pkcs8_der().to_vec()))
-        .next()
-        .expect("provided tls key must be valid");
-
-    let mut server_config = rustls::server::ServerConfig::builder()
-        .with_cipher_suites(&tls_settings.rustls_cipher_suites())
-        .with_kx_groups(&tls_settings.rustls_kx_groups())
-        .with_protocol_versions(&tls_settings.rustls_protocol_versions())
-        .expect("tls config must be valid. If this fails, perhaps an invalid cipher suite and protocol version combo are configured")
-        .with_no_client_auth()
-        .with_single_cert(tls_certs, tls_key)
-        .expect("tls config must be valid");
-
-    // We have to set this ourselves since we're building the [`ServerConfig`] from scratch
-    server_config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
-
-    RustlsConfig::from_config(Arc::new(server_config))
+/// Serve the router on the socket of a listener in HTTPS if it has a TLS config, or in HTTP otherwise
+async fn serve(
+    listener_state: &'static ListenerState,
+    socket: ListenSocket,
+    router: Router,
+    handle: Handle,
+    shutdown: tokio::sync::watch::Receiver<Option<Duration>>,
+) {
+    let listener = listener_state.listen_address.to_string();
+
+    let acceptor = listener_state.tls_config.clone().map(|tls_config| {
+        if let Some(serving_certificate) = &*listener_state.serving_certificate.read() {
+            tracing::info!(
+                listener,
+                subject = serving_certificate.subject,
+                not_after = serving_certificate.not_after.to_rfc3339(),
+                "serving https with tls certificate"
+            );
+        }
+        utils::ClientAuthAcceptor::new(tls_config, listener.clone())
+    });
+
+    if acceptor.is_none() {
+        tracing::warn!(listener, "no cert file provided in settings.toml, running server in HTTP mode");
+    }
+
+    match (socket, acceptor) {
+        (ListenSocket::Tcp(tcp_listener), Some(acceptor)) => {
+            axum_server::from_tcp(tcp_listener)
+                .acceptor(acceptor)
+                .handle(handle)
+                .serve(router.into_make_service())
+                .await
+        }
+        (ListenSocket::Tcp(tcp_listener), None) => axum_server::from_tcp(tcp_listener).handle(handle).serve(router.into_make_service()).await,
+        (ListenSocket::Unix(unix_listener), acceptor) => utils::serve_unix(unix_listener, acceptor, router, shutdown).await,
+    }
+    .expect("failed starting server");
+}
+
+/// Stop accepting connections on SIGTERM or SIGINT and give in-flight requests some time to finish
+async fn shutdown_on_signal(handle: Handle, shutdown: tokio::sync::watch::Sender<Option<Duration>>, global_state: &GlobalState) {
+    let mut sigterm = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()).expect("failed installing the SIGTERM handler");
+
+    let signal = tokio::select! {
+        _ = sigterm.recv() => "SIGTERM",
+        _ = tokio::signal::ctrl_c() => "SIGINT",
+    };
+    let drain_timeout = global_state.settings().web.drain_timeout;
+
+    tracing::info!(
+        signal,
+        drain_timeout_millis = drain_timeout.as_millis() as u64,
+        "received shutdown signal, draining connections"
+    );
+    let _ = sd_notify::notify(false, &[sd_notify::NotifyState::Stopping]);
+
+    handle.graceful_shutdown(Some(drain_timeout));
+    let _ = shutdown.send(Some(drain_timeout));
 }
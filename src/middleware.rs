@@ -19,15 +19,30 @@
 
 use std::time::Instant;
 
+use argon2::{Argon2, PasswordHash, PasswordVerifier};
 use axum::{
     body::HttpBody,
-    extract::{MatchedPath, Request},
+    extract::{MatchedPath, Request, State},
+    http::{header, HeaderMap, StatusCode},
     middleware::Next,
-    response::Response,
+    response::{IntoResponse, Response},
+};
+use axum_extra::headers::{
+    authorization::{Basic, Bearer},
+    Authorization, HeaderMapExt,
 };
 use metrics::{counter, histogram};
+use ring::{
+    constant_time::verify_slices_are_equal,
+    digest::{digest, SHA256},
+    hmac,
+};
 
-use crate::app_metrics::{REQUESTS_TOTAL, REQUEST_SECONDS, REQUEST_SIZE, RESPONSE_SIZE};
+use crate::{
+    app_metrics::{REQUESTS_TOTAL, REQUEST_SECONDS, REQUEST_SIZE, RESPONSE_SIZE},
+    global_state::GlobalState,
+    settings::app_settings::{Auth, Scope},
+};
 
 pub async fn logging(request: Request, next: Next) -> Response {
     let matched_path = if let Some(matched_path) = request.extensions().get::<MatchedPath>() {
@@ -73,3 +88,161 @@ pub async fn logging(request: Request, next: Next) -> Response {
 
     response
 }
+
+/// Reject requests whose credentials don't grant the given scope, if users or tokens are configured in the settings.
+///
+/// Added with `route_layer` to each group of routes so unknown routes still return 404 instead of 401
+pub async fn require_scope(State((global_state, scope)): State<(&'static GlobalState, Scope)>, request: Request, next: Next) -> Response {
+    let settings = global_state.settings();
+
+    if !settings.auth.enabled() {
+        return next.run(request).await;
+    }
+
+    let Some((principal, scopes)) = authenticate(global_state, &settings.auth, request.headers()).await else {
+        return (
+            StatusCode::UNAUTHORIZED,
+            [(
+                header::WWW_AUTHENTICATE,
+                concat!(r#"Basic realm=""#, env!("CARGO_CRATE_NAME"), r#"", charset="UTF-8""#),
+            )],
+        )
+            .into_response();
+    };
+
+    if !scopes.contains(&scope) {
+        tracing::warn!(principal, scope = scope.as_str(), "denied request missing the required scope");
+        return StatusCode::FORBIDDEN.into_response();
+    }
+
+    next.run(request).await
+}
+
+/// Find the user or token the request authenticates as, and return its name and scopes
+async fn authenticate<'a>(global_state: &GlobalState, auth: &'a Auth, headers: &HeaderMap) -> Option<(&'a str, &'a [Scope])> {
+    if let Some(Authorization(bearer)) = headers.typed_get::<Authorization<Bearer>>() {
+        // Comparing fixed length digests in constant time so neither the length nor the content of the tokens leaks through timing
+        let token_digest = digest(&SHA256, bearer.token().as_bytes());
+        let token = auth
+            .tokens
+            .iter()
+            .find(|token| verify_slices_are_equal(digest(&SHA256, token.token.as_bytes()).as_ref(), token_digest.as_ref()).is_ok());
+
+        if token.is_none() {
+            tracing::warn!("rejected request with an unknown bearer token");
+        }
+        return token.map(|token| (token.name.as_str(), token.scopes.as_slice()));
+    }
+
+    let Authorization(basic) = headers.typed_get::<Authorization<Basic>>()?;
+    let Some(user) = auth.users.iter().find(|user| user.username == basic.username()) else {
+        tracing::warn!(username = basic.username(), "rejected request with an unknown username");
+        return None;
+    };
+
+    // Including the hash so changing the password in the settings invalidates the cached verification
+    let cache_key = hmac::sign(
+        &global_state.verified_passwords_key,
+        [basic.username(), basic.password(), &user.password_hash].join("\0").as_bytes(),
+    )
+    .as_ref()
+    .to_vec();
+
+    if !global_state.verified_passwords.read().contains(&cache_key) {
+        let password = basic.password().to_string();
+        let password_hash = user.password_hash.clone();
+
+        // Verifying a hash deliberately takes a while, so keep it off the async worker threads
+        let verified = tokio::task::spawn_blocking(move || verify_password(&password, &password_hash))
+            .await
+            .unwrap_or(false);
+
+        if !verified {
+            tracing::warn!(username = basic.username(), "rejected request with a wrong password");
+            return None;
+        }
+        global_state.verified_passwords.write().insert(cache_key);
+    }
+
+    Some((user.username.as_str(), user.scopes.as_slice()))
+}
+
+fn verify_password(password: &str, password_hash: &str) -> bool {
+    if password_hash.starts_with("$argon2") {
+        PasswordHash::new(password_hash).is_ok_and(|hash| Argon2::default().verify_password(password.as_bytes(), &hash).is_ok())
+    } else {
+        bcrypt::verify(password, password_hash).unwrap_or(false)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use argon2::{password_hash::SaltString, PasswordHasher};
+    use axum::{routing::get, Router};
+
+    use super::*;
+
+    #[test]
+    fn verify_password_checks_bcrypt_and_argon2_hashes() {
+        let bcrypt_hash = bcrypt::hash("secret", 4).unwrap();
+        let salt = SaltString::from_b64("c29tZXNhbHRzb21lc2FsdA").unwrap();
+        let argon2_hash = Argon2::default().hash_password(b"secret", &salt).unwrap().to_string();
+
+        for password_hash in [bcrypt_hash, argon2_hash] {
+            assert!(verify_password("secret", &password_hash), "{password_hash}");
+            assert!(!verify_password("wrong", &password_hash), "{password_hash}");
+        }
+        assert!(!verify_password("secret", "not a hash"));
+    }
+
+    #[tokio::test]
+    async fn routes_reject_credentials_missing_their_scope() {
+        let global_state: &'static GlobalState = Box::leak(Box::new(GlobalState::from_toml(&format!(
+            "[[tenants]]\ntenant_id = \"t1\"\nclient_id = \"c1\"\ntoken_source = \"managed_identity\"\n\
+             [[auth.users]]\nusername = \"alice\"\npassword_hash = \"{}\"\nscopes = [\"api\"]\n\
+             [[auth.tokens]]\nname = \"prometheus\"\ntoken = \"scrape-token\"\nscopes = [\"metrics\"]\n",
+            bcrypt::hash("secret", 4).unwrap()
+        ))));
+
+        let route = |path, scope| {
+            Router::new()
+                .route(path, get(|| async { "ok" }))
+                .route_layer(axum::middleware::from_fn_with_state((global_state, scope), require_scope))
+        };
+        let router = route("/metrics", Scope::Metrics)
+            .merge(route("/api/apps", Scope::Api))
+            .merge(route("/api/settings", Scope::Admin));
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, router).await });
+
+        let client = reqwest::Client::new();
+        let status = |path: &str, auth: Option<(&str, Option<&str>)>, bearer: Option<&str>| {
+            let mut request = client.get(format!("http://{address}{path}"));
+            if let Some((username, password)) = auth {
+                request = request.basic_auth(username, password);
+            }
+            if let Some(token) = bearer {
+                request = request.bearer_auth(token);
+            }
+            async move { request.send().await.unwrap().status() }
+        };
+
+        assert_eq!(status("/metrics", None, None).await, StatusCode::UNAUTHORIZED);
+        assert_eq!(status("/metrics", None, Some("scrape-token")).await, StatusCode::OK);
+        assert_eq!(status("/api/apps", None, Some("scrape-token")).await, StatusCode::FORBIDDEN);
+        assert_eq!(status("/metrics", None, Some("wrong-token")).await, StatusCode::UNAUTHORIZED);
+
+        // Twice, to also go through the cached verification
+        for _ in 0..2 {
+            assert_eq!(status("/api/apps", Some(("alice", Some("secret"))), None).await, StatusCode::OK);
+            assert_eq!(
+                status("/api/settings", Some(("alice", Some("secret"))), None).await,
+                StatusCode::FORBIDDEN
+            );
+        }
+        assert_eq!(status("/api/apps", Some(("alice", Some("wrong"))), None).await, StatusCode::UNAUTHORIZED);
+        assert_eq!(status("/api/apps", Some(("bob", Some("secret"))), None).await, StatusCode::UNAUTHORIZED);
+    }
+}
@@ -21,32 +21,109 @@ use std::collections::HashMap;
 
 use axum::{
     extract::{Path, State},
-    http::StatusCode,
+    http::{HeaderMap, StatusCode},
 };
 use axum_extra::{response::ErasedJson, TypedHeader};
 
-use crate::{global_state::GlobalState, utils::FromSwaggerUi};
+use crate::{
+    global_state::{CacheStatus, GlobalState},
+    utils::{cache_status_headers, FromSwaggerUi},
+};
 
-/// Show all Azure applications cached in the exporter (truncated in Swagger UI to 50 entries)
+/// Show all Azure applications of all tenants cached in the exporter (truncated in Swagger UI to 50 entries)
 ///
-/// Call this endpoint outside Swagger UI to see full response
-#[utoipa::path(get, tag = "Applications", path = "/api/apps", responses((status = OK, body = HashMap<String, AzureApplication>)))]
-pub async fn get_all_applications(State(global_state): State<&GlobalState>, from_swagger: Option<TypedHeader<FromSwaggerUi>>) -> ErasedJson {
-    let applications = global_state.applications.read().expect("lock poisoned");
+/// Call this endpoint outside Swagger UI to see full response. The cache headers describe the least fresh cache of all tenants
+#[utoipa::path(get, tag = "Applications", path = "/api/apps",
+    responses((status = OK, body = HashMap<String, AzureApplication>, headers(
+            ("x-cache-last-success" = String, description = "When the cache was last refreshed successfully, if ever"),
+            ("x-cache-consecutive-failures" = u64, description = "Failed cache refreshes since the last successful one"),
+            ("x-cache-stale" = bool, description = "Whether the data is from before a failed refresh, or there was no successful refresh yet")
+        )))
+)]
+pub async fn get_all_applications(
+    State(global_state): State<&GlobalState>,
+    from_swagger: Option<TypedHeader<FromSwaggerUi>>,
+) -> (HeaderMap, ErasedJson) {
+    let status = CacheStatus::combine(
+        global_state
+            .tenants
+            .iter()
+            .filter(|tenant| global_state.tenant_settings(tenant).applications.enabled)
+            .map(|tenant| tenant.applications_status.read().clone()),
+    );
+    let headers = cache_status_headers(&status);
+
+    let tenant_applications = global_state.tenants.iter().map(|tenant| tenant.applications.read()).collect::<Vec<_>>();
+    let applications = tenant_applications.iter().flat_map(|applications| applications.iter());
+
     if from_swagger.is_some() {
-        ErasedJson::new(applications.iter().take(50).collect::<HashMap<_, _>>())
+        (headers, ErasedJson::new(applications.take(50).collect::<HashMap<_, _>>()))
     } else {
-        ErasedJson::new(&*applications)
+        (headers, ErasedJson::new(applications.collect::<HashMap<_, _>>()))
     }
 }
 
-/// Show Azure application by ID
+/// Show Azure application by ID, searching all tenants
 #[utoipa::path(get, tag = "Applications", path = "/api/apps/{id}",
     params(("id" = String, Path, description = "ID of Azure application to lookup")),
     responses((status = OK, body = AzureApplication), (status = NOT_FOUND, description = "No application found by the given ID"))
 )]
 pub async fn get_application_by_id(State(global_state): State<&GlobalState>, Path(id): Path<String>) -> Result<ErasedJson, StatusCode> {
-    if let Some(app) = global_state.applications.read().expect("lock poisoned").get(&id) {
+    for tenant in global_state.tenants.iter() {
+        if let Some(app) = tenant.applications.read().get(&id) {
+            return Ok(ErasedJson::new(app));
+        }
+    }
+
+    Err(StatusCode::NOT_FOUND)
+}
+
+/// Show all Azure applications of a tenant cached in the exporter (truncated in Swagger UI to 50 entries)
+///
+/// Call this endpoint outside Swagger UI to see full response
+#[utoipa::path(get, tag = "Applications", path = "/api/tenants/{tenant}/apps",
+    params(("tenant" = String, Path, description = "ID of the Azure tenant")),
+    responses(
+        (status = OK, body = HashMap<String, AzureApplication>, headers(
+            ("x-cache-last-success" = String, description = "When the cache was last refreshed successfully, if ever"),
+            ("x-cache-consecutive-failures" = u64, description = "Failed cache refreshes since the last successful one"),
+            ("x-cache-stale" = bool, description = "Whether the data is from before a failed refresh, or there was no successful refresh yet")
+        )),
+        (status = NOT_FOUND, description = "No tenant found by the given ID")
+    )
+)]
+pub async fn get_tenant_applications(
+    State(global_state): State<&GlobalState>,
+    Path(tenant): Path<String>,
+    from_swagger: Option<TypedHeader<FromSwaggerUi>>,
+) -> Result<(HeaderMap, ErasedJson), StatusCode> {
+    let tenant = global_state.tenant(&tenant).ok_or(StatusCode::NOT_FOUND)?;
+
+    let headers = cache_status_headers(&tenant.applications_status.read());
+
+    let applications = tenant.applications.read();
+    if from_swagger.is_some() {
+        Ok((headers, ErasedJson::new(applications.iter().take(50).collect::<HashMap<_, _>>())))
+    } else {
+        Ok((headers, ErasedJson::new(&*applications)))
+    }
+}
+
+/// Show Azure application of a tenant by ID
+#[utoipa::path(get, tag = "Applications", path = "/api/tenants/{tenant}/apps/{id}",
+    params(
+        ("tenant" = String, Path, description = "ID of the Azure tenant"),
+        ("id" = String, Path, description = "ID of Azure application to lookup")
+    ),
+    responses((status = OK, body = AzureApplication), (status = NOT_FOUND, description = "No tenant or application found by the given IDs"))
+)]
+pub async fn get_tenant_application_by_id(
+    State(global_state): State<&GlobalState>,
+    Path((tenant, id)): Path<(String, String)>,
+) -> Result<ErasedJson, StatusCode> {
+    let tenant = global_state.tenant(&tenant).ok_or(StatusCode::NOT_FOUND)?;
+
+    if let Some(app) = tenant.applications.read().get(&id) {
         Ok(ErasedJson::new(app))
     } else {
         Err(StatusCode::NOT_FOUND)
@@ -0,0 +1,121 @@
+/*
+ * Licensed to the Apache Software Foundation (ASF) under one
+ * or more contributor license agreements.  See the NOTICE file
+ * distributed with this work for additional information
+ * regarding copyright ownership.  The ASF licenses this file
+ * to you under the Apache License, Version 2.0 (the
+ * "License"); you may not use this file except in compliance
+ * with the License.  You may obtain a copy of the License at
+ *
+ *   http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing,
+ * software distributed under the License is distributed on an
+ * "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+ * KIND, either express or implied.  See the License for the
+ * specific language governing permissions and limitations
+ * under the License.
+ */
+
+use axum::{extract::State, Json};
+use serde::Serialize;
+use utoipa::ToSchema;
+
+use crate::{
+    global_state::GlobalState,
+    types::lenient::{DecodeFailure, LenientCredential},
+};
+
+/// An Azure object that was skipped, or a credential that is kept without its dates, because it failed to decode
+#[derive(Debug, Serialize, ToSchema)]
+pub struct DecodeError {
+    pub tenant_id: String,
+    /// "application", "service_principal", "password_credential" or "key_credential"
+    pub object: &'static str,
+    /// ID of the object, or of the object owning the credential
+    pub id: Option<String>,
+    /// Key ID of the credential
+    pub key_id: Option<String>,
+    pub error: String,
+}
+
+/// Show the Azure objects and credentials of all tenants that failed to decode in the last refreshes
+#[utoipa::path(get, tag = "Diagnostics", path = "/api/diagnostics/decode-errors", responses((status = OK, body = Vec<DecodeError>)))]
+pub async fn get_decode_errors(State(global_state): State<&GlobalState>) -> Json<Vec<DecodeError>> {
+    let mut decode_errors = Vec::new();
+
+    for tenant in global_state.tenants.iter() {
+        let failures = |object, failures: &[DecodeFailure]| {
+            failures
+                .iter()
+                .map(|failure| DecodeError {
+                    tenant_id: tenant.tenant_id.clone(),
+                    object,
+                    id: failure.id.clone(),
+                    key_id: None,
+                    error: failure.error.clone(),
+                })
+                .collect::<Vec<_>>()
+        };
+
+        decode_errors.extend(failures("application", &tenant.application_decode_failures.read()));
+        decode_errors.extend(failures("service_principal", &tenant.service_principal_decode_failures.read()));
+
+        for application in tenant.applications.read().values() {
+            credential_errors(
+                &mut decode_errors,
+                &tenant.tenant_id,
+                "password_credential",
+                &application.id,
+                &application.password_credentials,
+            );
+            credential_errors(
+                &mut decode_errors,
+                &tenant.tenant_id,
+                "key_credential",
+                &application.id,
+                &application.key_credentials,
+            );
+        }
+
+        for service_principal in tenant.service_principals.read().values() {
+            let id = &service_principal.id;
+            credential_errors(
+                &mut decode_errors,
+                &tenant.tenant_id,
+                "password_credential",
+                id,
+                &service_principal.password_credentials,
+            );
+            credential_errors(
+                &mut decode_errors,
+                &tenant.tenant_id,
+                "key_credential",
+                id,
+                &service_principal.key_credentials,
+            );
+        }
+    }
+
+    Json(decode_errors)
+}
+
+fn credential_errors<T: LenientCredential>(
+    decode_errors: &mut Vec<DecodeError>,
+    tenant_id: &str,
+    object: &'static str,
+    owner_id: &str,
+    credentials: &[T],
+) {
+    for credential in credentials {
+        if let Some(error) = credential.decode_error() {
+            decode_errors.push(DecodeError {
+                tenant_id: tenant_id.to_string(),
+                object,
+                id: Some(owner_id.to_string()),
+                key_id: Some(credential.key_id().to_string()),
+                error: error.to_string(),
+            });
+        }
+    }
+}
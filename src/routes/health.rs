@@ -0,0 +1,134 @@
+/*
+ * Licensed to the Apache Software Foundation (ASF) under one
+ * or more contributor license agreements.  See the NOTICE file
+ * distributed with this work for additional information
+ * regarding copyright ownership.  The ASF licenses this file
+ * to you under the Apache License, Version 2.0 (the
+ * "License"); you may not use this file except in compliance
+ * with the License.  You may obtain a copy of the License at
+ *
+ *   http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing,
+ * software distributed under the License is distributed on an
+ * "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+ * KIND, either express or implied.  See the License for the
+ * specific language governing permissions and limitations
+ * under the License.
+ */
+
+use std::time::Duration;
+
+use axum::{extract::State, http::StatusCode, Json};
+use chrono::Utc;
+use serde::Serialize;
+use utoipa::ToSchema;
+
+use crate::global_state::{CacheStatus, GlobalState, TaskState};
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct HealthReport {
+    /// Whether all checks passed
+    pub healthy: bool,
+    pub checks: Vec<HealthCheck>,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct HealthCheck {
+    /// What was checked, e.g. "applications/{tenant_id}"
+    pub name: String,
+    pub healthy: bool,
+    pub message: String,
+}
+
+impl HealthReport {
+    fn new(checks: Vec<HealthCheck>) -> (StatusCode, Json<Self>) {
+        let healthy = checks.iter().all(|check| check.healthy);
+        let status = if healthy { StatusCode::OK } else { StatusCode::SERVICE_UNAVAILABLE };
+
+        (status, Json(Self { healthy, checks }))
+    }
+}
+
+/// Liveness of the exporter, i.e. whether the background tasks updating the caches and metrics are running and not crashed
+#[utoipa::path(get, tag = "Health", path = "/healthz", security(()),
+    responses((status = OK, body = HealthReport), (status = SERVICE_UNAVAILABLE, description = "A background task crashed", body = HealthReport))
+)]
+pub async fn healthz(State(global_state): State<&GlobalState>) -> (StatusCode, Json<HealthReport>) {
+    let checks = global_state
+        .background_tasks
+        .read()
+        .iter()
+        .map(|task| HealthCheck {
+            name: task.name.clone(),
+            healthy: task.state == TaskState::Running,
+            message: format!("{}, restarted {} times", task.state.as_str(), task.restarts),
+        })
+        .collect();
+
+    HealthReport::new(checks)
+}
+
+/// Readiness of the exporter, i.e. whether it has an API token and recent enough caches of all tenants to export metrics from
+#[utoipa::path(get, tag = "Health", path = "/readyz", security(()),
+    responses((status = OK, body = HealthReport), (status = SERVICE_UNAVAILABLE, description = "A cache is missing or too old", body = HealthReport))
+)]
+pub async fn readyz(State(global_state): State<&GlobalState>) -> (StatusCode, Json<HealthReport>) {
+    let mut checks = Vec::new();
+
+    for tenant in global_state.tenants.iter() {
+        let tenant_settings = global_state.tenant_settings(tenant);
+
+        if tenant_settings.applications.enabled || tenant_settings.service_principals.enabled {
+            let acquired = !tenant.azure_api_token.read().is_empty();
+
+            checks.push(HealthCheck {
+                name: format!("api_token/{}", tenant.tenant_id),
+                healthy: acquired,
+                message: if acquired { "acquired" } else { "not yet acquired" }.into(),
+            });
+        }
+
+        if tenant_settings.applications.enabled {
+            let status = tenant.applications_status.read();
+            let max_age = max_cache_age(global_state, tenant_settings.applications.cache_refresh_interval);
+            checks.push(cache_check(format!("applications/{}", tenant.tenant_id), &status, max_age));
+        }
+
+        if tenant_settings.service_principals.enabled {
+            let status = tenant.service_principals_status.read();
+            let max_age = max_cache_age(global_state, tenant_settings.service_principals.cache_refresh_interval);
+            checks.push(cache_check(format!("service_principals/{}", tenant.tenant_id), &status, max_age));
+        }
+    }
+
+    HealthReport::new(checks)
+}
+
+fn max_cache_age(global_state: &GlobalState, cache_refresh_interval: Duration) -> Duration {
+    global_state.settings().health.max_cache_age.unwrap_or(cache_refresh_interval * 3)
+}
+
+fn cache_check(name: String, status: &CacheStatus, max_age: Duration) -> HealthCheck {
+    let Some(last_success) = status.last_success else {
+        return HealthCheck {
+            name,
+            healthy: false,
+            message: "no successful refresh yet".into(),
+        };
+    };
+
+    let age_seconds = (Utc::now() - last_success).num_seconds();
+    let healthy = age_seconds <= max_age.as_secs() as i64;
+
+    let message = if healthy {
+        format!("last successful refresh {age_seconds}s ago")
+    } else {
+        format!(
+            "last successful refresh {age_seconds}s ago, more than the max age of {}s",
+            max_age.as_secs()
+        )
+    };
+
+    HealthCheck { name, healthy, message }
+}
@@ -17,22 +17,54 @@
  * under the License.
  */
 
-use axum::Extension;
+use axum::{extract::State, Extension};
 use axum_extra::TypedHeader;
-use metrics_exporter_prometheus::PrometheusHandle;
+use metrics_exporter_prometheus::{formatting, PrometheusHandle};
 
-use crate::utils::FromSwaggerUi;
+use crate::{app_metrics::CREDENTIAL_METRICS, global_state::GlobalState, tasks, utils::FromSwaggerUi};
 
 /// Show the Prometheus metrics (truncated in Swagger UI to 20KiB)
 ///
 /// Call this endpoint outside Swagger UI to see full response
 #[utoipa::path(get, tag = "Metrics", path = "/metrics", responses((status = OK, body = String)))]
-pub async fn metrics(metric_handle: Extension<PrometheusHandle>, from_swagger: Option<TypedHeader<FromSwaggerUi>>) -> String {
+pub async fn metrics(
+    State(global_state): State<&GlobalState>,
+    metric_handle: Extension<PrometheusHandle>,
+    from_swagger: Option<TypedHeader<FromSwaggerUi>>,
+) -> String {
     let mut metrics = metric_handle.render();
 
+    if global_state.compute_metrics_on_scrape {
+        render_credential_metrics(global_state, &mut metrics);
+    }
+
     if from_swagger.is_some() {
         metrics.truncate(1024 * 20)
     }
 
     metrics
 }
+
+/// Append the credential gauges computed from the caches, grouped by metric as the exposition format requires
+fn render_credential_metrics(global_state: &GlobalState, output: &mut String) {
+    let samples = tasks::credential_metric_samples(global_state);
+
+    for (name, description) in CREDENTIAL_METRICS {
+        let mut metric_samples = samples.iter().filter(|sample| sample.name == *name).peekable();
+        if metric_samples.peek().is_none() {
+            continue;
+        }
+
+        formatting::write_help_line(output, name, description);
+        formatting::write_type_line(output, name, "gauge");
+        for sample in metric_samples {
+            let labels: Vec<String> = sample
+                .labels
+                .iter()
+                .map(|(key, value)| format!("{key}=\"{}\"", formatting::sanitize_label_value(value)))
+                .collect();
+            formatting::write_metric_line::<&str, f64>(output, name, None, &labels, None, sample.value);
+        }
+        output.push('\n');
+    }
+}
@@ -18,9 +18,19 @@
  */
 
 pub mod applications;
+pub mod diagnostics;
+pub mod health;
 pub mod metrics;
+pub mod policies;
+pub mod reload;
+pub mod service_principals;
 pub mod settings;
 
 pub use applications::*;
+pub use diagnostics::*;
+pub use health::*;
 pub use metrics::*;
+pub use policies::*;
+pub use reload::*;
+pub use service_principals::*;
 pub use settings::*;
@@ -0,0 +1,36 @@
+/*
+ * Licensed to the Apache Software Foundation (ASF) under one
+ * or more contributor license agreements.  See the NOTICE file
+ * distributed with this work for additional information
+ * regarding copyright ownership.  The ASF licenses this file
+ * to you under the Apache License, Version 2.0 (the
+ * "License"); you may not use this file except in compliance
+ * with the License.  You may obtain a copy of the License at
+ *
+ *   http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing,
+ * software distributed under the License is distributed on an
+ * "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+ * KIND, either express or implied.  See the License for the
+ * specific language governing permissions and limitations
+ * under the License.
+ */
+
+use axum::{extract::State, Json};
+
+use crate::{global_state::GlobalState, types::policies::PolicyViolation};
+
+/// Show the applications and password credentials of all tenants that violate the `[[policies]]` settings, with the reason.
+///
+/// Policies are evaluated after each refresh of the applications cache
+#[utoipa::path(get, tag = "Policies", path = "/api/policies/violations", responses((status = OK, body = Vec<PolicyViolation>)))]
+pub async fn get_policy_violations(State(global_state): State<&GlobalState>) -> Json<Vec<PolicyViolation>> {
+    let violations = global_state
+        .tenants
+        .iter()
+        .flat_map(|tenant| tenant.policy_violations.read().clone())
+        .collect();
+
+    Json(violations)
+}
@@ -0,0 +1,131 @@
+/*
+ * Licensed to the Apache Software Foundation (ASF) under one
+ * or more contributor license agreements.  See the NOTICE file
+ * distributed with this work for additional information
+ * regarding copyright ownership.  The ASF licenses this file
+ * to you under the Apache License, Version 2.0 (the
+ * "License"); you may not use this file except in compliance
+ * with the License.  You may obtain a copy of the License at
+ *
+ *   http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing,
+ * software distributed under the License is distributed on an
+ * "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+ * KIND, either express or implied.  See the License for the
+ * specific language governing permissions and limitations
+ * under the License.
+ */
+
+use axum::{extract::State, http::StatusCode, Json};
+use serde::Serialize;
+use utoipa::ToSchema;
+
+use crate::{global_state::GlobalState, tasks};
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct ReloadResult {
+    pub reloaded: bool,
+    /// Why the settings file was rejected, if it was
+    pub error: Option<String>,
+}
+
+/// Reload the settings file. Invalid settings are rejected and the current settings are kept
+#[utoipa::path(post, tag = "Admin", path = "/-/reload",
+    responses(
+        (status = OK, body = ReloadResult),
+        (status = UNPROCESSABLE_ENTITY, description = "The settings file is invalid", body = ReloadResult),
+        (status = INTERNAL_SERVER_ERROR, description = "The reload failed unexpectedly", body = ReloadResult)
+    )
+)]
+pub async fn reload(State(global_state): State<&'static GlobalState>) -> (StatusCode, Json<ReloadResult>) {
+    // Reading the files and building the TLS configs blocks, so keep it off the async worker threads
+    let (status, result) = match tokio::task::spawn_blocking(move || tasks::reload(global_state, "api")).await {
+        Ok(Ok(())) => (StatusCode::OK, Ok(())),
+        Ok(Err(e)) => (StatusCode::UNPROCESSABLE_ENTITY, Err(e)),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, Err(format!("reload task failed: {e}"))),
+    };
+
+    (
+        status,
+        Json(ReloadResult {
+            reloaded: result.is_ok(),
+            error: result.err(),
+        }),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::settings::app_settings;
+
+    const TENANT: &str = "[[tenants]]\ntenant_id = \"t1\"\nclient_id = \"c1\"\ntoken_source = \"managed_identity\"\n";
+
+    /// Reloads re-read the settings file, so each test writes its own and removes it afterwards
+    async fn reload_with(name: &str, new_settings_contents: &str) -> (StatusCode, ReloadResult, &'static GlobalState) {
+        let settings_path = std::env::temp_dir().join(format!("azure_app_exporter_{}_{name}.toml", std::process::id()));
+        let settings_path = settings_path.to_string_lossy().to_string();
+        std::fs::write(&settings_path, TENANT).unwrap();
+
+        let settings = app_settings::parse(&settings_path).unwrap();
+        let global_state: &'static GlobalState = Box::leak(Box::new(GlobalState::from_settings(settings_path.clone(), settings).unwrap()));
+
+        std::fs::write(&settings_path, new_settings_contents).unwrap();
+        let (status, Json(result)) = reload(State(global_state)).await;
+        std::fs::remove_file(&settings_path).unwrap();
+
+        (status, result, global_state)
+    }
+
+    #[tokio::test]
+    async fn invalid_settings_are_rejected_with_the_reason() {
+        let (status, result, global_state) = reload_with(
+            "reload_invalid",
+            &format!("{TENANT}[metrics]\nprune_interval = \"soon\"\nrefresh_interval = \"1m\"\nexpiry_windows = []\n"),
+        )
+        .await;
+
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+        assert!(!result.reloaded);
+        assert!(result.error.unwrap().contains("prune_interval"));
+        assert_eq!(
+            global_state.settings().metrics.prune_interval,
+            Some(std::time::Duration::from_secs(30 * 60))
+        );
+        assert!(!global_state.settings_reload_status.read().successful);
+    }
+
+    #[tokio::test]
+    async fn adding_or_removing_tenants_is_rejected() {
+        let added = format!("{TENANT}[[tenants]]\ntenant_id = \"t2\"\nclient_id = \"c2\"\ntoken_source = \"managed_identity\"\n");
+        let removed = TENANT.replace("t1", "t2");
+
+        for (name, new_settings_contents) in [("reload_add_tenant", added), ("reload_remove_tenant", removed)] {
+            let (status, result, global_state) = reload_with(name, &new_settings_contents).await;
+
+            assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY, "{name}");
+            assert_eq!(result.error.as_deref(), Some("adding or removing tenants requires a restart"), "{name}");
+            let tenant_ids = global_state
+                .settings()
+                .tenants
+                .iter()
+                .map(|tenant| tenant.credentials.tenant_id.clone())
+                .collect::<Vec<_>>();
+            assert_eq!(tenant_ids, ["t1"], "{name}");
+        }
+    }
+
+    #[tokio::test]
+    async fn valid_settings_are_applied() {
+        let (status, result, global_state) = reload_with(
+            "reload_valid",
+            &format!("{TENANT}[metrics]\nprune_interval = \"2h\"\nrefresh_interval = \"1m\"\nexpiry_windows = []\n"),
+        )
+        .await;
+
+        assert_eq!(status, StatusCode::OK, "{:?}", result.error);
+        assert!(result.reloaded && result.error.is_none());
+        assert_eq!(global_state.settings().metrics.prune_interval, Some(std::time::Duration::from_secs(7200)));
+    }
+}
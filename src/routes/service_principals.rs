@@ -0,0 +1,135 @@
+/*
+ * Licensed to the Apache Software Foundation (ASF) under one
+ * or more contributor license agreements.  See the NOTICE file
+ * distributed with this work for additional information
+ * regarding copyright ownership.  The ASF licenses this file
+ * to you under the Apache License, Version 2.0 (the
+ * "License"); you may not use this file except in compliance
+ * with the License.  You may obtain a copy of the License at
+ *
+ *   http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing,
+ * software distributed under the License is distributed on an
+ * "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+ * KIND, either express or implied.  See the License for the
+ * specific language governing permissions and limitations
+ * under the License.
+ */
+
+use std::collections::HashMap;
+
+use axum::{
+    extract::{Path, State},
+    http::{HeaderMap, StatusCode},
+};
+use axum_extra::{response::ErasedJson, TypedHeader};
+
+use crate::{
+    global_state::{CacheStatus, GlobalState},
+    utils::{cache_status_headers, FromSwaggerUi},
+};
+
+/// Show all Azure service principals of all tenants cached in the exporter (truncated in Swagger UI to 50 entries)
+///
+/// Call this endpoint outside Swagger UI to see full response. The cache headers describe the least fresh cache of all tenants
+#[utoipa::path(get, tag = "Service principals", path = "/api/service-principals",
+    responses((status = OK, body = HashMap<String, AzureServicePrincipal>, headers(
+            ("x-cache-last-success" = String, description = "When the cache was last refreshed successfully, if ever"),
+            ("x-cache-consecutive-failures" = u64, description = "Failed cache refreshes since the last successful one"),
+            ("x-cache-stale" = bool, description = "Whether the data is from before a failed refresh, or there was no successful refresh yet")
+        )))
+)]
+pub async fn get_all_service_principals(
+    State(global_state): State<&GlobalState>,
+    from_swagger: Option<TypedHeader<FromSwaggerUi>>,
+) -> (HeaderMap, ErasedJson) {
+    let status = CacheStatus::combine(
+        global_state
+            .tenants
+            .iter()
+            .filter(|tenant| global_state.tenant_settings(tenant).service_principals.enabled)
+            .map(|tenant| tenant.service_principals_status.read().clone()),
+    );
+    let headers = cache_status_headers(&status);
+
+    let tenant_service_principals = global_state
+        .tenants
+        .iter()
+        .map(|tenant| tenant.service_principals.read())
+        .collect::<Vec<_>>();
+    let service_principals = tenant_service_principals.iter().flat_map(|service_principals| service_principals.iter());
+
+    if from_swagger.is_some() {
+        (headers, ErasedJson::new(service_principals.take(50).collect::<HashMap<_, _>>()))
+    } else {
+        (headers, ErasedJson::new(service_principals.collect::<HashMap<_, _>>()))
+    }
+}
+
+/// Show Azure service principal by ID, searching all tenants
+#[utoipa::path(get, tag = "Service principals", path = "/api/service-principals/{id}",
+    params(("id" = String, Path, description = "ID of Azure service principal to lookup")),
+    responses((status = OK, body = AzureServicePrincipal), (status = NOT_FOUND, description = "No service principal found by the given ID"))
+)]
+pub async fn get_service_principal_by_id(State(global_state): State<&GlobalState>, Path(id): Path<String>) -> Result<ErasedJson, StatusCode> {
+    for tenant in global_state.tenants.iter() {
+        if let Some(service_principal) = tenant.service_principals.read().get(&id) {
+            return Ok(ErasedJson::new(service_principal));
+        }
+    }
+
+    Err(StatusCode::NOT_FOUND)
+}
+
+/// Show all Azure service principals of a tenant cached in the exporter (truncated in Swagger UI to 50 entries)
+///
+/// Call this endpoint outside Swagger UI to see full response
+#[utoipa::path(get, tag = "Service principals", path = "/api/tenants/{tenant}/service-principals",
+    params(("tenant" = String, Path, description = "ID of the Azure tenant")),
+    responses(
+        (status = OK, body = HashMap<String, AzureServicePrincipal>, headers(
+            ("x-cache-last-success" = String, description = "When the cache was last refreshed successfully, if ever"),
+            ("x-cache-consecutive-failures" = u64, description = "Failed cache refreshes since the last successful one"),
+            ("x-cache-stale" = bool, description = "Whether the data is from before a failed refresh, or there was no successful refresh yet")
+        )),
+        (status = NOT_FOUND, description = "No tenant found by the given ID")
+    )
+)]
+pub async fn get_tenant_service_principals(
+    State(global_state): State<&GlobalState>,
+    Path(tenant): Path<String>,
+    from_swagger: Option<TypedHeader<FromSwaggerUi>>,
+) -> Result<(HeaderMap, ErasedJson), StatusCode> {
+    let tenant = global_state.tenant(&tenant).ok_or(StatusCode::NOT_FOUND)?;
+
+    let headers = cache_status_headers(&tenant.service_principals_status.read());
+
+    let service_principals = tenant.service_principals.read();
+    if from_swagger.is_some() {
+        Ok((headers, ErasedJson::new(service_principals.iter().take(50).collect::<HashMap<_, _>>())))
+    } else {
+        Ok((headers, ErasedJson::new(&*service_principals)))
+    }
+}
+
+/// Show Azure service principal of a tenant by ID
+#[utoipa::path(get, tag = "Service principals", path = "/api/tenants/{tenant}/service-principals/{id}",
+    params(
+        ("tenant" = String, Path, description = "ID of the Azure tenant"),
+        ("id" = String, Path, description = "ID of Azure service principal to lookup")
+    ),
+    responses((status = OK, body = AzureServicePrincipal), (status = NOT_FOUND, description = "No tenant or service principal found by the given IDs"))
+)]
+pub async fn get_tenant_service_principal_by_id(
+    State(global_state): State<&GlobalState>,
+    Path((tenant, id)): Path<(String, String)>,
+) -> Result<ErasedJson, StatusCode> {
+    let tenant = global_state.tenant(&tenant).ok_or(StatusCode::NOT_FOUND)?;
+
+    if let Some(service_principal) = tenant.service_principals.read().get(&id) {
+        Ok(ErasedJson::new(service_principal))
+    } else {
+        Err(StatusCode::NOT_FOUND)
+    }
+}
@@ -17,12 +17,13 @@
  * under the License.
  */
 
-use axum::{extract::State, Json};
+use axum::extract::State;
+use axum_extra::response::ErasedJson;
 
-use crate::{global_state::GlobalState, settings::app_settings::Settings};
+use crate::global_state::GlobalState;
 
 /// Show the exporter settings, except sensitive values
 #[utoipa::path(get, tag = "Info", path = "/api/settings", responses((status = OK, body = Settings)))]
-pub async fn show_settings(State(global_state): State<&GlobalState>) -> Json<&Settings> {
-    Json(&global_state.settings)
+pub async fn show_settings(State(global_state): State<&GlobalState>) -> ErasedJson {
+    ErasedJson::new(&*global_state.settings())
 }
@@ -17,28 +17,53 @@
  * under the License.
  */
 
-use std::{net::SocketAddr, path::PathBuf, time::Duration};
+use std::{path::PathBuf, time::Duration};
 
+use regex::Regex;
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use utoipa::ToSchema;
 
-use crate::settings::tls_parser::{CipherSuite, KxGroup, ProtocolVersion};
+use crate::{
+    app_metrics::{CREDENTIAL_LABELS, SUMMARY_LABELS},
+    settings::{
+        listen_address::ListenAddress,
+        tls_parser::{CipherSuite, KxGroup, ProtocolVersion},
+    },
+};
 
 #[derive(Debug, Deserialize, Serialize, ToSchema)]
 pub struct Settings {
     // "inline" allows showing only the "Settings" schema in the Swagger UI without the other structs since they are never returned by themselves.
     // And we also don't need to add the other structs to components(schemas(...)) in main.rs
     #[schema(inline)]
-    pub credentials: Credentials,
+    pub tenants: Vec<Tenant>,
 
     #[serde(default)]
     #[schema(inline)]
     pub metrics: Metrics,
 
+    #[serde(default)]
+    #[schema(inline)]
+    pub health: Health,
+
+    /// Default applications settings of tenants that do not specify their own
     #[serde(default)]
     #[schema(inline)]
     pub applications: Applications,
 
+    /// Default service principals settings of tenants that do not specify their own
+    #[serde(default)]
+    #[schema(inline)]
+    pub service_principals: ServicePrincipals,
+
+    #[serde(default)]
+    #[schema(inline)]
+    pub retry: Retry,
+
+    #[serde(default)]
+    #[schema(inline)]
+    pub reload: Reload,
+
     #[serde(default)]
     #[schema(inline)]
     pub web: Web,
@@ -51,12 +76,104 @@ pub struct Settings {
     #[schema(inline)]
     pub tls: Tls,
 
+    #[serde(default)]
+    #[schema(inline)]
+    pub auth: Auth,
+
+    #[serde(default)]
+    #[schema(inline)]
+    pub policies: Vec<Policy>,
+
     #[serde(default)]
     #[schema(inline)]
     pub debug: Debug,
 }
 
-fn hide_client_secret<T, S: Serializer>(_value: &T, serializer: S) -> Result<S::Ok, S::Error> {
+impl Settings {
+    pub fn tenant(&self, tenant_id: &str) -> Option<&Tenant> {
+        self.tenants.iter().find(|tenant| tenant.credentials.tenant_id == tenant_id)
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, ToSchema)]
+pub struct Tenant {
+    #[serde(flatten)]
+    #[schema(inline)]
+    pub credentials: Credentials,
+
+    #[serde(default)]
+    #[schema(inline)]
+    pub applications: Applications,
+
+    #[serde(default)]
+    #[schema(inline)]
+    pub service_principals: ServicePrincipals,
+}
+
+/// Rules the cached applications are checked against after each refresh of the applications cache.
+/// A policy can combine several rules, each breach of a rule is reported as a separate violation
+#[derive(Debug, Deserialize, Serialize, ToSchema)]
+pub struct Policy {
+    /// The `policy` label of the violation metric
+    pub name: String,
+
+    /// Only check applications whose display name matches this regular expression. All applications by default
+    #[serde(default, serialize_with = "ser_regex", deserialize_with = "de_regex")]
+    #[schema(value_type = Option<String>, example = "^prod-")]
+    pub display_name_regex: Option<Regex>,
+
+    /// Only check the applications with these app IDs. All applications by default
+    #[serde(default)]
+    pub app_ids: Vec<String>,
+
+    /// Password credentials must not be valid for longer than this from their start date, and must have both dates
+    #[serde(default, serialize_with = "ser_optional_duration", deserialize_with = "humantime_serde::deserialize")]
+    #[schema(value_type = Option<String>, example = "365d")]
+    pub max_password_lifetime: Option<Duration>,
+
+    /// Applications must not have more password credentials which have started and not expired yet
+    #[serde(default)]
+    pub max_active_passwords: Option<usize>,
+
+    /// Applications must authenticate with certificates, so they need an active certificate and any active password credential is a violation
+    #[serde(default)]
+    pub require_certificates: bool,
+}
+
+impl Policy {
+    fn has_rules(&self) -> bool {
+        self.max_password_lifetime.is_some() || self.max_active_passwords.is_some() || self.require_certificates
+    }
+}
+
+fn ser_regex<S: Serializer>(value: &Option<Regex>, serializer: S) -> Result<S::Ok, S::Error> {
+    match value {
+        Some(regex) => serializer.serialize_str(regex.as_str()),
+        None => serializer.serialize_none(),
+    }
+}
+
+fn de_regex<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Regex>, D::Error> {
+    let regex = String::deserialize(deserializer)?;
+
+    Regex::new(&regex).map(Some).map_err(serde::de::Error::custom)
+}
+
+fn ser_optional_duration<S: Serializer>(value: &Option<Duration>, serializer: S) -> Result<S::Ok, S::Error> {
+    match value {
+        Some(duration) => serializer.serialize_str(&format_duration(*duration)),
+        None => serializer.serialize_none(),
+    }
+}
+
+fn hide_client_secret<T, S: Serializer>(value: &Option<T>, serializer: S) -> Result<S::Ok, S::Error> {
+    match value {
+        Some(_) => serializer.serialize_str("******"),
+        None => serializer.serialize_none(),
+    }
+}
+
+fn hide_secret<T, S: Serializer>(_value: &T, serializer: S) -> Result<S::Ok, S::Error> {
     serializer.serialize_str("******")
 }
 
@@ -68,17 +185,125 @@ fn verify_credential_present<'de, D: Deserializer<'de>>(deserializer: D) -> Resu
     }
 }
 
-#[derive(Debug, Deserialize, Serialize, ToSchema)]
+fn verify_optional_credential_present<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<String>, D::Error> {
+    verify_credential_present(deserializer).map(Some)
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize, ToSchema)]
 pub struct Credentials {
     #[serde(deserialize_with = "verify_credential_present")]
     pub tenant_id: String,
 
+    /// For managed identities, this is the client ID of the managed identity
     #[serde(deserialize_with = "verify_credential_present")]
     pub client_id: String,
 
+    /// How to get the API token. Defaults to "certificate" if a certificate file is set, otherwise "client_secret"
+    #[schema(inline)]
+    pub token_source: TokenSource,
+
     #[serde(serialize_with = "hide_client_secret")] // Do not leak the client secret when exposing our credentials on an API endpoint
-    #[serde(deserialize_with = "verify_credential_present")]
-    pub client_secret: String,
+    #[serde(deserialize_with = "verify_optional_credential_present", default)]
+    pub client_secret: Option<String>,
+
+    /// PEM or PKCS#12 (PFX) file with the certificate registered on the application, used instead of a client secret
+    #[schema(value_type = Option<String>)]
+    pub certificate_file: Option<PathBuf>,
+
+    /// PEM file with the RSA private key of the certificate. Not needed for PKCS#12 files or PEM files which also contain the private key
+    #[schema(value_type = Option<String>)]
+    pub private_key_file: Option<PathBuf>,
+
+    /// Password of the PKCS#12 file, if any
+    #[serde(serialize_with = "hide_client_secret", default)]
+    pub certificate_password: Option<String>,
+
+    /// File with the federated token of a workload identity. Defaults to the AZURE_FEDERATED_TOKEN_FILE env var
+    #[schema(value_type = Option<String>)]
+    pub federated_token_file: Option<PathBuf>,
+
+    /// Endpoint to get managed identity tokens from, i.e. the Azure Instance Metadata Service (IMDS)
+    #[serde(default = "default_managed_identity_url")]
+    pub managed_identity_url: String,
+
+    /// The national cloud the tenant lives in, which determines the authority host and Microsoft Graph URL
+    #[serde(default)]
+    #[schema(inline)]
+    pub cloud: Cloud,
+
+    /// Microsoft Entra ID host to get API tokens from. Derived from the cloud unless it is "custom"
+    #[schema(example = "https://login.microsoftonline.com")]
+    pub authority_host: String,
+
+    /// Microsoft Graph base URL, also used for the token scope. Derived from the cloud unless it is "custom"
+    #[schema(example = "https://graph.microsoft.com")]
+    pub graph_url: String,
+}
+
+fn default_managed_identity_url() -> String {
+    "http://169.254.169.254/metadata/identity/oauth2/token".into()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum TokenSource {
+    /// Client credentials flow with a client secret
+    ClientSecret,
+    /// Client credentials flow with a JWT signed by a certificate
+    Certificate,
+    /// Client credentials flow with a federated token, like the ones projected into pods by AKS workload identity
+    WorkloadIdentity,
+    /// Token from the managed identity endpoint of the Azure VM or AKS node the exporter runs on
+    ManagedIdentity,
+}
+
+impl TokenSource {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TokenSource::ClientSecret => "client_secret",
+            TokenSource::Certificate => "certificate",
+            TokenSource::WorkloadIdentity => "workload_identity",
+            TokenSource::ManagedIdentity => "managed_identity",
+        }
+    }
+}
+
+/// https://learn.microsoft.com/en-us/graph/deployments
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize, ToSchema)]
+#[serde(rename_all = "kebab-case")]
+pub enum Cloud {
+    /// Azure global service
+    #[default]
+    Public,
+    /// Azure US Government L4, i.e. GCC High
+    Usgov,
+    /// Azure US Government L5, i.e. DoD
+    UsgovDod,
+    /// Azure China operated by 21Vianet
+    China,
+    /// Any other cloud, with the authority host and Graph URL given explicitly
+    Custom,
+}
+
+impl Cloud {
+    pub fn authority_host(&self) -> Option<&'static str> {
+        match self {
+            Cloud::Public => Some("https://login.microsoftonline.com"),
+            Cloud::Usgov | Cloud::UsgovDod => Some("https://login.microsoftonline.us"),
+            Cloud::China => Some("https://login.chinacloudapi.cn"),
+            Cloud::Custom => None,
+        }
+    }
+
+    pub fn graph_url(&self) -> Option<&'static str> {
+        match self {
+            Cloud::Public => Some("https://graph.microsoft.com"),
+            Cloud::Usgov => Some("https://graph.microsoft.us"),
+            Cloud::UsgovDod => Some("https://dod-graph.microsoft.us"),
+            Cloud::China => Some("https://microsoftgraph.chinacloudapi.cn"),
+            Cloud::Custom => None,
+        }
+    }
 }
 
 #[derive(Debug, Deserialize, Serialize, ToSchema)]
@@ -90,6 +315,24 @@ pub struct Metrics {
     #[serde(with = "humantime_serde")]
     #[schema(example = "1m")]
     pub refresh_interval: Duration,
+
+    /// Compute the credential gauges from the cached applications and service principals on every scrape of `/metrics`,
+    /// instead of setting them every `refresh_interval`. Their values are exact and removed credentials disappear immediately
+    #[serde(default)]
+    pub compute_on_scrape: bool,
+
+    /// Windows of the `..._credentials_expiring` gauges, which count the credentials expiring within each of them
+    #[serde(
+        default = "default_expiry_windows",
+        serialize_with = "ser_expiry_windows",
+        deserialize_with = "de_expiry_windows"
+    )]
+    #[schema(value_type = Vec<String>, example = json!(["7d", "30d", "90d"]))]
+    pub expiry_windows: Vec<Duration>,
+
+    #[serde(default)]
+    #[schema(inline)]
+    pub labels: MetricLabels,
 }
 
 impl Default for Metrics {
@@ -97,8 +340,136 @@ impl Default for Metrics {
         Self {
             prune_interval: Some(Duration::from_secs(60 * 30)),
             refresh_interval: Duration::from_secs(60),
+            compute_on_scrape: false,
+            expiry_windows: default_expiry_windows(),
+            labels: Default::default(),
+        }
+    }
+}
+
+fn default_expiry_windows() -> Vec<Duration> {
+    [7, 30, 90].iter().map(|days| Duration::from_secs(60 * 60 * 24 * days)).collect()
+}
+
+fn ser_expiry_windows<S: Serializer>(value: &[Duration], serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.collect_seq(value.iter().map(|window| format_duration(*window)))
+}
+
+/// Format the duration in its largest whole unit, like "30d" or "12h", since humantime would turn 90 days into months and hours
+// `u64::is_multiple_of` needs Rust 1.87, which is newer than the toolchains the exporter is built with
+#[allow(clippy::manual_is_multiple_of)]
+pub fn format_duration(duration: Duration) -> String {
+    let seconds = duration.as_secs();
+
+    for (unit, suffix) in [(60 * 60 * 24, "d"), (60 * 60, "h"), (60, "m")] {
+        if seconds % unit == 0 {
+            return format!("{}{suffix}", seconds / unit);
         }
     }
+
+    format!("{seconds}s")
+}
+
+/// Sort the windows, so the gauges are exported in order, and drop duplicates which would be counted twice
+fn de_expiry_windows<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<Duration>, D::Error> {
+    let mut windows: Vec<Duration> = Vec::<humantime_serde::Serde<Duration>>::deserialize(deserializer)?
+        .into_iter()
+        .map(|window| window.into_inner())
+        .collect();
+
+    if windows.iter().any(|window| window.is_zero()) {
+        return Err(serde::de::Error::custom("expiry_windows must not contain a zero duration"));
+    }
+    windows.sort();
+    windows.dedup();
+
+    Ok(windows)
+}
+
+/// Which labels the credential metrics have. Labels which do not apply to a metric, like `certificate_thumbprint` on the password metrics, are skipped.
+/// Credentials with the same labels are combined into one series with the soonest expiry, or the largest age and lifetime
+#[derive(Debug, Deserialize, Serialize, ToSchema)]
+#[serde(default)]
+pub struct MetricLabels {
+    /// Labels of the `..._remaining_seconds` gauges. All labels by default
+    #[serde(deserialize_with = "check_credential_labels")]
+    pub remaining_seconds: Vec<String>,
+
+    /// Labels of the `..._expiry_timestamp_seconds` gauges. Only the labels identifying the credential by default
+    #[serde(deserialize_with = "check_credential_labels")]
+    pub expiry_timestamp: Vec<String>,
+
+    /// Labels of the `azure_credential_info` metric, to join descriptive labels onto the other metrics. All labels except the end dates by default
+    #[serde(deserialize_with = "check_credential_labels")]
+    pub credential_info: Vec<String>,
+
+    /// Labels of the `..._credential_age_seconds`, `..._credential_lifetime_seconds` and `..._application_age_seconds` gauges.
+    /// Only the labels identifying the credential or application, `object` and `credential_type` by default
+    #[serde(deserialize_with = "check_credential_labels")]
+    pub age: Vec<String>,
+
+    /// Labels the `..._credentials_expired`, `..._credentials_expiring`, `..._credentials_without_end_date`, `..._credentials_undecodable`
+    /// and `..._applications_without_credentials` gauges are partitioned by. All of them by default, an empty list exports the totals
+    #[serde(deserialize_with = "check_summary_labels")]
+    pub summary: Vec<String>,
+}
+
+impl Default for MetricLabels {
+    fn default() -> Self {
+        let labels = |names: &[&str]| names.iter().map(|name| name.to_string()).collect();
+        let identifying_labels = labels(&["tenant_id", "id", "app_id", "password_key_id", "certificate_key_id"]);
+        // The age and lifetime gauges are shared by all kinds of credentials, so they need to tell them apart
+        let age_labels = labels(&[
+            "tenant_id",
+            "id",
+            "app_id",
+            "password_key_id",
+            "certificate_key_id",
+            "object",
+            "credential_type",
+        ]);
+
+        Self {
+            remaining_seconds: labels(CREDENTIAL_LABELS),
+            expiry_timestamp: identifying_labels.clone(),
+            credential_info: CREDENTIAL_LABELS
+                .iter()
+                .filter(|name| !name.ends_with("_end_date_time"))
+                .map(|name| name.to_string())
+                .collect(),
+            age: age_labels,
+            summary: labels(SUMMARY_LABELS),
+        }
+    }
+}
+
+fn check_credential_labels<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<String>, D::Error> {
+    check_labels(deserializer, CREDENTIAL_LABELS)
+}
+
+fn check_summary_labels<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<String>, D::Error> {
+    check_labels(deserializer, SUMMARY_LABELS)
+}
+
+fn check_labels<'de, D: Deserializer<'de>>(deserializer: D, known_labels: &[&str]) -> Result<Vec<String>, D::Error> {
+    let labels = Vec::<String>::deserialize(deserializer)?;
+
+    match labels.iter().find(|label| !known_labels.contains(&label.as_str())) {
+        Some(label) => Err(serde::de::Error::custom(format!(
+            "unknown label {label}, expected one of {}",
+            known_labels.join(", ")
+        ))),
+        None => Ok(labels),
+    }
+}
+
+#[derive(Debug, Default, Deserialize, Serialize, ToSchema)]
+#[serde(default)]
+pub struct Health {
+    /// How old a cache can get before the exporter is not ready anymore. Defaults to 3x the cache refresh interval
+    #[serde(with = "humantime_serde")]
+    #[schema(value_type = Option<String>, example = "45m")]
+    pub max_cache_age: Option<Duration>,
 }
 
 /// Enforce that the given value is within the supported range
@@ -111,7 +482,7 @@ fn de_results_per_page<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u16
     }
 }
 
-#[derive(Debug, Deserialize, Serialize, ToSchema)]
+#[derive(Debug, Clone, Deserialize, Serialize, ToSchema)]
 #[serde(default)]
 pub struct Applications {
     pub enabled: bool,
@@ -120,11 +491,15 @@ pub struct Applications {
     #[schema(value_type = String, example = "15m", default = "15m")]
     pub cache_refresh_interval: Duration,
 
+    /// Defaults to the applications API of the tenant's Graph URL
     pub url: String,
 
     #[serde(deserialize_with = "de_results_per_page")]
     #[schema(minimum = 1, maximum = 999)]
     pub results_per_page: u16,
+
+    /// Only fetch the applications that changed since the last refresh using delta queries
+    pub delta_query: bool,
 }
 
 impl Default for Applications {
@@ -134,6 +509,85 @@ impl Default for Applications {
             cache_refresh_interval: Duration::from_secs(60 * 15),
             url: "https://graph.microsoft.com/v1.0/applications".into(),
             results_per_page: 999,
+            delta_query: false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, ToSchema)]
+#[serde(default)]
+pub struct ServicePrincipals {
+    pub enabled: bool,
+
+    #[serde(with = "humantime_serde")]
+    #[schema(value_type = String, example = "15m", default = "15m")]
+    pub cache_refresh_interval: Duration,
+
+    /// Defaults to the service principals API of the tenant's Graph URL
+    pub url: String,
+
+    #[serde(deserialize_with = "de_results_per_page")]
+    #[schema(minimum = 1, maximum = 999)]
+    pub results_per_page: u16,
+}
+
+impl Default for ServicePrincipals {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            cache_refresh_interval: Duration::from_secs(60 * 15),
+            url: "https://graph.microsoft.com/v1.0/servicePrincipals".into(),
+            results_per_page: 999,
+        }
+    }
+}
+
+/// Retries of throttled and otherwise failed requests to Azure
+#[derive(Debug, Deserialize, Serialize, ToSchema)]
+#[serde(default)]
+pub struct Retry {
+    /// How many times to retry a single request, e.g. a single page of applications. 0 disables retries
+    pub max_retries: u32,
+
+    /// Backoff before the first retry, doubled on every further retry. Not used if Azure sends a Retry-After header
+    #[serde(with = "humantime_serde")]
+    #[schema(value_type = String, example = "1s", default = "1s")]
+    pub initial_backoff: Duration,
+
+    /// Longest backoff between retries, also for the duration in a Retry-After header
+    #[serde(with = "humantime_serde")]
+    #[schema(value_type = String, example = "1m", default = "1m")]
+    pub max_backoff: Duration,
+}
+
+impl Default for Retry {
+    fn default() -> Self {
+        Self {
+            max_retries: 5,
+            initial_backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(60),
+        }
+    }
+}
+
+/// Reloading the settings without restarting. Reloads can also be triggered with SIGHUP or `POST /-/reload`
+#[derive(Debug, Deserialize, Serialize, ToSchema)]
+#[serde(default)]
+pub struct Reload {
+    /// Whether to reload the settings when the settings file changes, and the TLS certificate when the cert or key file changes
+    pub watch: bool,
+
+    /// How often to check whether the settings file changed
+    #[serde(with = "humantime_serde")]
+    #[schema(value_type = String, example = "10s", default = "10s")]
+    pub watch_interval: Duration,
+}
+
+impl Default for Reload {
+    fn default() -> Self {
+        Self {
+            watch: true,
+            watch_interval: Duration::from_secs(10),
         }
     }
 }
@@ -141,22 +595,122 @@ impl Default for Applications {
 #[derive(Debug, Deserialize, Serialize, ToSchema)]
 #[serde(default)]
 pub struct Web {
+    /// TCP address like "0.0.0.0:9081", or Unix domain socket like "unix:/run/azure_app_exporter.sock"
+    #[schema(value_type = String)]
+    pub listen_address: ListenAddress,
+
+    /// Permissions of the Unix domain socket, in octal like "660". Without write permission, clients cannot connect
+    #[serde(serialize_with = "ser_socket_mode", deserialize_with = "de_socket_mode")]
+    #[schema(value_type = Option<String>, example = "660")]
+    pub socket_mode: Option<u32>,
+
+    /// PEM file with the certificate chain, or PKCS#12 (PFX) file with the certificate chain and key. Serves HTTPS if set
+    #[schema(value_type = Option<String>)]
+    pub cert_file: Option<PathBuf>,
+
+    /// PEM file with the private key of the certificate. Not needed for PKCS#12 files or PEM files which also contain the private key
+    #[schema(value_type = Option<String>)]
+    pub key_file: Option<PathBuf>,
+
+    /// Password of the PKCS#12 file, if any
+    #[serde(serialize_with = "hide_client_secret")]
+    pub cert_password: Option<String>,
+
+    /// How long in-flight requests get to finish on shutdown before their connections are closed
+    #[serde(with = "humantime_serde")]
+    #[schema(value_type = String, example = "30s", default = "30s")]
+    pub drain_timeout: Duration,
+
+    /// Serve the endpoints on multiple addresses, e.g. the metrics for Prometheus and the API only on localhost.
+    /// If set, `listen_address`, `socket_mode`, `cert_file`, `key_file` and `cert_password` above are ignored
+    #[schema(inline)]
+    pub listeners: Vec<Listener>,
+}
+
+impl Web {
+    /// The configured listeners, or a single listener serving every endpoint on `listen_address` if none are configured
+    pub fn listeners(&self) -> Vec<Listener> {
+        if !self.listeners.is_empty() {
+            return self.listeners.clone();
+        }
+
+        vec![Listener {
+            listen_address: self.listen_address.clone(),
+            socket_mode: self.socket_mode,
+            endpoints: default_endpoints(),
+            cert_file: self.cert_file.clone(),
+            key_file: self.key_file.clone(),
+            cert_password: self.cert_password.clone(),
+            tls: None,
+        }]
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, ToSchema)]
+pub struct Listener {
     #[schema(value_type = String)]
-    pub listen_address: SocketAddr,
+    pub listen_address: ListenAddress,
+
+    #[serde(serialize_with = "ser_socket_mode", deserialize_with = "de_socket_mode", default)]
+    #[schema(value_type = Option<String>, example = "660")]
+    pub socket_mode: Option<u32>,
 
+    /// Which endpoints to serve. The Swagger UI and OpenAPI docs are served along with "api", and `/healthz` and `/readyz` on every listener
+    #[serde(default = "default_endpoints")]
+    #[schema(inline)]
+    pub endpoints: Vec<Scope>,
+
+    /// Same as `cert_file` of the `[web]` settings. Serves HTTPS if set
     #[schema(value_type = Option<String>)]
     pub cert_file: Option<PathBuf>,
 
     #[schema(value_type = Option<String>)]
     pub key_file: Option<PathBuf>,
+
+    #[serde(serialize_with = "hide_client_secret")]
+    pub cert_password: Option<String>,
+
+    /// TLS settings of this listener, like its client CA. The top-level `[tls]` settings are used if not set
+    #[schema(inline)]
+    pub tls: Option<Tls>,
+}
+
+impl Listener {
+    pub fn tls<'a>(&'a self, settings: &'a Settings) -> &'a Tls {
+        self.tls.as_ref().unwrap_or(&settings.tls)
+    }
+}
+
+fn default_endpoints() -> Vec<Scope> {
+    vec![Scope::Metrics, Scope::Api, Scope::Admin]
+}
+
+fn ser_socket_mode<S: Serializer>(value: &Option<u32>, serializer: S) -> Result<S::Ok, S::Error> {
+    match value {
+        Some(mode) => serializer.serialize_str(&format!("{mode:o}")),
+        None => serializer.serialize_none(),
+    }
+}
+
+fn de_socket_mode<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<u32>, D::Error> {
+    let mode = String::deserialize(deserializer)?;
+
+    match u32::from_str_radix(&mode, 8) {
+        Ok(mode) if mode <= 0o777 => Ok(Some(mode)),
+        _ => Err(serde::de::Error::custom(r#"socket_mode must be octal permissions like "660""#)),
+    }
 }
 
 impl Default for Web {
     fn default() -> Self {
         Self {
             listen_address: "0.0.0.0:9081".parse().expect("hardcoded value must parse"),
+            socket_mode: Default::default(),
             cert_file: Default::default(),
             key_file: Default::default(),
+            cert_password: Default::default(),
+            drain_timeout: Duration::from_secs(30),
+            listeners: Default::default(),
         }
     }
 }
@@ -171,7 +725,7 @@ fn check_url<'de, D: Deserializer<'de>>(d: D) -> Result<String, D::Error> {
     }
 }
 
-#[derive(Debug, Deserialize, Serialize, ToSchema)]
+#[derive(Debug, PartialEq, Deserialize, Serialize, ToSchema)]
 #[serde(default)]
 pub struct OpenApi {
     pub enabled: bool,
@@ -193,7 +747,7 @@ impl Default for OpenApi {
     }
 }
 
-#[derive(Debug, Deserialize, Serialize, ToSchema)]
+#[derive(Debug, Clone, Deserialize, Serialize, ToSchema)]
 #[serde(default)]
 pub struct Tls {
     #[schema(inline)]
@@ -204,6 +758,28 @@ pub struct Tls {
 
     #[schema(inline)]
     pub protocol_versions: Vec<ProtocolVersion>,
+
+    /// PEM file with the CA certificates to verify the certificates of clients with. Enables mutual TLS if set
+    #[schema(value_type = Option<String>)]
+    pub client_ca_file: Option<PathBuf>,
+
+    #[schema(inline)]
+    pub client_auth: ClientAuth,
+
+    /// Only allow client certificates with one of these subjects, like "CN=prometheus", or subject alternative names.
+    /// Any certificate issued by the client CA is allowed if empty
+    pub allowed_client_names: Vec<String>,
+}
+
+/// Whether clients have to present a certificate if a client CA file is set
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum ClientAuth {
+    /// Reject clients without a valid certificate
+    #[default]
+    Required,
+    /// Allow clients without a certificate, but reject clients with an invalid one
+    Optional,
 }
 
 impl Tls {
@@ -239,28 +815,402 @@ impl Default for Tls {
             ],
             key_exchange_groups: vec![X25519, SECP256R1, SECP384R1],
             protocol_versions: vec![TLS13, TLS12],
+            client_ca_file: Default::default(),
+            client_auth: Default::default(),
+            allowed_client_names: Default::default(),
+        }
+    }
+}
+
+/// Authentication of the HTTP API. Every route except the health checks and the OpenAPI docs requires credentials
+/// once at least one user or token is configured
+#[derive(Debug, Default, Deserialize, Serialize, ToSchema)]
+#[serde(default)]
+pub struct Auth {
+    /// Users authenticating with HTTP basic auth
+    #[schema(inline)]
+    pub users: Vec<User>,
+
+    /// Static tokens authenticating with `Authorization: Bearer <token>`
+    #[schema(inline)]
+    pub tokens: Vec<Token>,
+}
+
+impl Auth {
+    pub fn enabled(&self) -> bool {
+        !self.users.is_empty() || !self.tokens.is_empty()
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, ToSchema)]
+pub struct User {
+    pub username: String,
+
+    /// bcrypt (`$2b$...`) or argon2 (`$argon2id$...`) hash of the password
+    #[serde(serialize_with = "hide_secret", deserialize_with = "check_password_hash")]
+    pub password_hash: String,
+
+    #[schema(inline)]
+    pub scopes: Vec<Scope>,
+}
+
+#[derive(Debug, Deserialize, Serialize, ToSchema)]
+pub struct Token {
+    /// Only used for logging which token authenticated a request
+    pub name: String,
+
+    #[serde(serialize_with = "hide_secret", deserialize_with = "verify_credential_present")]
+    pub token: String,
+
+    #[schema(inline)]
+    pub scopes: Vec<Scope>,
+}
+
+/// A group of endpoints, which users and tokens need the scope of to access them and which listeners serve
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum Scope {
+    /// `/metrics`
+    Metrics,
+    /// The applications, service principals, diagnostics and policy violations under `/api`
+    Api,
+    /// `/api/settings` and `/-/reload`
+    Admin,
+}
+
+impl Scope {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Scope::Metrics => "metrics",
+            Scope::Api => "api",
+            Scope::Admin => "admin",
         }
     }
 }
 
+fn check_password_hash<'de, D: Deserializer<'de>>(deserializer: D) -> Result<String, D::Error> {
+    let password_hash = String::deserialize(deserializer)?;
+
+    let valid = if password_hash.starts_with("$argon2") {
+        argon2::PasswordHash::new(&password_hash).is_ok_and(|hash| hash.hash.is_some())
+    } else {
+        password_hash.parse::<bcrypt::HashParts>().is_ok()
+    };
+
+    if valid {
+        Ok(password_hash)
+    } else {
+        Err(serde::de::Error::custom("password_hash must be a bcrypt or argon2 hash"))
+    }
+}
+
 #[derive(Debug, Default, Deserialize, Serialize, ToSchema)]
 #[serde(default)]
 pub struct Debug {
     pub no_verify_tls: bool,
 }
 
-pub fn parse() -> Settings {
+pub fn settings_path() -> String {
     let settings_env_var = "AZURE_APP_EXPORTER_SETTINGS_PATH";
     let default_settings_path = "/etc/azure_app_exporter/settings.toml";
 
-    let settings_path = std::env::var(settings_env_var).unwrap_or_else(|_| {
+    std::env::var(settings_env_var).unwrap_or_else(|_| {
         tracing::warn!("no {settings_env_var} env var set, defaulting to {default_settings_path}");
         default_settings_path.into()
-    });
+    })
+}
+
+/// Read and validate the settings file. This is done at startup and on every reload, so errors are returned instead of panicking
+pub fn parse(settings_path: &str) -> Result<Settings, String> {
+    let settings_contents = std::fs::read_to_string(settings_path).map_err(|e| format!("failed reading {settings_path}: {e}"))?;
+
+    parse_contents(settings_path, &settings_contents)
+}
+
+/// Parse and validate the contents of a settings file. The path is only used in error messages
+pub fn parse_contents(settings_path: &str, settings_contents: &str) -> Result<Settings, String> {
+    let mut settings_table: toml::Table = toml::from_str(settings_contents).map_err(|e| format!("failed parsing {settings_path}: {e}"))?;
+    normalize_tenants(&mut settings_table).map_err(|e| format!("failed parsing {settings_path}: {e}"))?;
+
+    let mut settings: Settings = settings_table.try_into().map_err(|e| format!("failed parsing {settings_path}: {e}"))?;
+
+    let listeners = settings.web.listeners();
+    for (i, listener) in listeners.iter().enumerate() {
+        if listeners[..i].iter().any(|l| l.listen_address == listener.listen_address) {
+            return Err(format!(
+                "failed parsing {settings_path}: listener {} is configured more than once",
+                listener.listen_address
+            ));
+        }
+    }
+
+    for (i, policy) in settings.policies.iter().enumerate() {
+        if settings.policies[..i].iter().any(|p| p.name == policy.name) {
+            return Err(format!(
+                "failed parsing {settings_path}: policy {} is configured more than once",
+                policy.name
+            ));
+        }
+        if !policy.has_rules() {
+            return Err(format!(
+                "failed parsing {settings_path}: policy {} requires at least one of max_password_lifetime, max_active_passwords or require_certificates",
+                policy.name
+            ));
+        }
+    }
+
+    for i in 0..settings.tenants.len() {
+        let (previous_tenants, tenants) = settings.tenants.split_at_mut(i);
+        let credentials = &mut tenants[0].credentials;
+
+        if previous_tenants.iter().any(|t| t.credentials.tenant_id == credentials.tenant_id) {
+            return Err(format!(
+                "failed parsing {settings_path}: tenant {} is configured more than once",
+                credentials.tenant_id
+            ));
+        }
+
+        if credentials.token_source == TokenSource::WorkloadIdentity && credentials.federated_token_file.is_none() {
+            credentials.federated_token_file = std::env::var_os("AZURE_FEDERATED_TOKEN_FILE").map(PathBuf::from);
+        }
+
+        let missing_setting = match credentials.token_source {
+            TokenSource::ClientSecret => credentials.client_secret.is_none().then_some("client_secret"),
+            TokenSource::Certificate => credentials.certificate_file.is_none().then_some("certificate_file"),
+            TokenSource::WorkloadIdentity => credentials
+                .federated_token_file
+                .is_none()
+                .then_some("federated_token_file or the AZURE_FEDERATED_TOKEN_FILE env var"),
+            TokenSource::ManagedIdentity => None,
+        };
+
+        if let Some(missing_setting) = missing_setting {
+            return Err(format!(
+                "failed parsing {settings_path}: tenant {} with token source {} requires {missing_setting}",
+                credentials.tenant_id,
+                credentials.token_source.as_str()
+            ));
+        }
+    }
+
+    Ok(settings)
+}
+
+/// Support both the single tenant layout with a top-level `[credentials]` section and the multi-tenant layout with `[[tenants]]` entries
+/// by moving the top-level credentials into the list of tenants.
+///
+/// Settings missing from the `applications` and `service_principals` sections of a tenant are inherited from the top-level sections,
+/// and the token source of a tenant is inferred from its credentials if not given.
+///
+/// The authority host, Graph URL and API URLs of a tenant are derived from its cloud if not given. The top-level API URLs are only inherited
+/// by tenants without their own `cloud` or `graph_url`, since they would point a tenant of another cloud at the wrong Graph API.
+fn normalize_tenants(settings_table: &mut toml::Table) -> Result<(), String> {
+    let mut tenants = match settings_table.remove("tenants") {
+        Some(toml::Value::Array(tenants)) => tenants,
+        Some(_) => return Err("tenants must be an array of tables, i.e. [[tenants]]".into()),
+        None => Vec::new(),
+    };
+
+    if let Some(credentials) = settings_table.remove("credentials") {
+        tenants.insert(0, credentials);
+    }
+
+    if tenants.is_empty() {
+        return Err("no [credentials] or [[tenants]] found".into());
+    }
+
+    for tenant in tenants.iter_mut() {
+        let tenant = tenant.as_table_mut().ok_or("credentials and tenants must be tables")?;
+
+        if !tenant.contains_key("token_source") {
+            let token_source = if tenant.contains_key("certificate_file") {
+                "certificate"
+            } else {
+                "client_secret"
+            };
+            tenant.insert("token_source".into(), token_source.into());
+        }
 
-    let settings_contents = std::fs::read_to_string(&settings_path).unwrap_or_else(|e| {
-        panic!("failed reading {settings_path}: {e}");
-    });
+        let own_graph_url = tenant.contains_key("cloud") || tenant.contains_key("graph_url");
 
-    toml::from_str(&settings_contents).unwrap_or_else(|e| panic!("failed parsing {settings_path}: {e}"))
+        let cloud: Cloud = match tenant.get("cloud") {
+            Some(cloud) => cloud.clone().try_into().map_err(|e| format!("invalid cloud: {e}"))?,
+            None => Cloud::default(),
+        };
+
+        for (key, cloud_default) in [("authority_host", cloud.authority_host()), ("graph_url", cloud.graph_url())] {
+            let value = match tenant.get(key) {
+                Some(value) => value.as_str().ok_or_else(|| format!("{key} must be a string"))?,
+                None => cloud_default.ok_or_else(|| format!(r#"{key} is required with cloud = "custom""#))?,
+            };
+            let value = value.trim_end_matches('/').to_string();
+            tenant.insert(key.into(), value.into());
+        }
+
+        let graph_url = tenant["graph_url"].as_str().expect("graph url was just inserted").to_string();
+
+        for (section, api) in [("applications", "applications"), ("service_principals", "servicePrincipals")] {
+            let tenant_section = tenant.entry(section).or_insert_with(|| toml::Value::Table(toml::Table::new()));
+            let tenant_section = tenant_section.as_table_mut().ok_or_else(|| format!("tenant {section} must be a table"))?;
+
+            if let Some(toml::Value::Table(defaults)) = settings_table.get(section) {
+                for (key, value) in defaults {
+                    if key == "url" && own_graph_url {
+                        continue;
+                    }
+                    if !tenant_section.contains_key(key) {
+                        tenant_section.insert(key.clone(), value.clone());
+                    }
+                }
+            }
+
+            if !tenant_section.contains_key("url") {
+                tenant_section.insert("url".into(), format!("{graph_url}/v1.0/{api}").into());
+            }
+        }
+    }
+
+    settings_table.insert("tenants".into(), toml::Value::Array(tenants));
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn user(password_hash: &str) -> Result<User, toml::de::Error> {
+        toml::from_str(&format!("username = \"alice\"\npassword_hash = '{password_hash}'\nscopes = [\"api\"]"))
+    }
+
+    #[test]
+    fn check_password_hash_accepts_bcrypt_and_argon2_hashes() {
+        let bcrypt_hash = "$2b$04$C2yLi2sG6Hg5TKhRxdsKne6bNLrgTdNFE.4t6r3k4ZKXFsw8cYnS6";
+        let argon2_hash = "$argon2id$v=19$m=19456,t=2,p=1$c29tZXNhbHRzb21lc2FsdA$pOC+Xy5bL2wvkaJtMzmzyAMHbWOptkHw2hv9zzBMWD0";
+
+        assert_eq!(user(bcrypt_hash).unwrap().password_hash, bcrypt_hash);
+        assert_eq!(user(argon2_hash).unwrap().password_hash, argon2_hash);
+    }
+
+    #[test]
+    fn check_password_hash_rejects_other_strings() {
+        for password_hash in ["secret", "$2b$04$tooshort", "$argon2id$garbage", ""] {
+            let error = user(password_hash).unwrap_err().to_string();
+            assert!(
+                error.contains("password_hash must be a bcrypt or argon2 hash"),
+                "{password_hash}: {error}"
+            );
+        }
+    }
+
+    #[test]
+    fn legacy_credentials_section_becomes_a_tenant() {
+        let settings = parse_contents(
+            "settings.toml",
+            "[credentials]\ntenant_id = \"t1\"\nclient_id = \"c1\"\nclient_secret = \"s1\"\n\
+             [applications]\ncache_refresh_interval = \"5m\"\nurl = \"http://localhost:8080/v1.0/applications\"\n",
+        )
+        .unwrap();
+
+        assert_eq!(settings.tenants.len(), 1);
+        let tenant = &settings.tenants[0];
+        assert_eq!(tenant.credentials.tenant_id, "t1");
+        assert_eq!(tenant.credentials.token_source, TokenSource::ClientSecret);
+        assert_eq!(tenant.applications.cache_refresh_interval, Duration::from_secs(300));
+        assert_eq!(tenant.applications.url, "http://localhost:8080/v1.0/applications");
+        assert_eq!(tenant.service_principals.url, "https://graph.microsoft.com/v1.0/servicePrincipals");
+    }
+
+    #[test]
+    fn top_level_url_is_not_inherited_by_tenants_of_another_cloud() {
+        let settings = parse_contents(
+            "settings.toml",
+            "[credentials]\ntenant_id = \"t1\"\nclient_id = \"c1\"\nclient_secret = \"s1\"\ncloud = \"usgov\"\n\
+             [applications]\nurl = \"https://graph.microsoft.com/v1.0/applications\"\n",
+        )
+        .unwrap();
+
+        assert_eq!(settings.tenants[0].applications.url, "https://graph.microsoft.us/v1.0/applications");
+    }
+
+    #[test]
+    fn tenant_configured_twice_is_rejected() {
+        let tenant = "[[tenants]]\ntenant_id = \"t1\"\nclient_id = \"c1\"\nclient_secret = \"s1\"\n";
+        let error = parse_contents("settings.toml", &tenant.repeat(2)).unwrap_err();
+        assert!(error.contains("tenant t1 is configured more than once"), "{error}");
+
+        // Also when one of them is the legacy section
+        let legacy_tenant = tenant.replace("[[tenants]]", "[credentials]");
+        let error = parse_contents("settings.toml", &format!("{legacy_tenant}{tenant}")).unwrap_err();
+        assert!(error.contains("tenant t1 is configured more than once"), "{error}");
+    }
+
+    #[test]
+    fn token_source_without_its_credentials_is_rejected() {
+        for (token_source, missing_setting) in [
+            ("client_secret", "client_secret"),
+            ("certificate", "certificate_file"),
+            ("workload_identity", "federated_token_file"),
+        ] {
+            let contents = format!("[[tenants]]\ntenant_id = \"t1\"\nclient_id = \"c1\"\ntoken_source = \"{token_source}\"\n");
+            let result = parse_contents("settings.toml", &contents);
+
+            // The federated token file can also come from the environment
+            if token_source == "workload_identity" && std::env::var_os("AZURE_FEDERATED_TOKEN_FILE").is_some() {
+                continue;
+            }
+            let error = result.unwrap_err();
+            assert!(
+                error.contains(&format!("with token source {token_source} requires {missing_setting}")),
+                "{error}"
+            );
+        }
+
+        let contents = "[[tenants]]\ntenant_id = \"t1\"\nclient_id = \"c1\"\ntoken_source = \"managed_identity\"\n";
+        assert!(parse_contents("settings.toml", contents).is_ok());
+    }
+
+    #[test]
+    fn socket_mode_is_octal() {
+        let listener =
+            |socket_mode: &str| toml::from_str::<Listener>(&format!("listen_address = \"unix:/run/test.sock\"\nsocket_mode = \"{socket_mode}\""));
+
+        assert_eq!(listener("660").unwrap().socket_mode, Some(0o660));
+        assert_eq!(listener("0600").unwrap().socket_mode, Some(0o600));
+        assert_eq!(listener("777").unwrap().socket_mode, Some(0o777));
+        for socket_mode in ["1777", "680", "rw-rw----", ""] {
+            let error = listener(socket_mode).unwrap_err().to_string();
+            assert!(error.contains("socket_mode must be octal permissions"), "{socket_mode}: {error}");
+        }
+    }
+
+    #[test]
+    fn expiry_windows_are_sorted_and_deduplicated() {
+        let metrics: Metrics =
+            toml::from_str("prune_interval = \"30m\"\nrefresh_interval = \"1m\"\nexpiry_windows = [\"30d\", \"1w\", \"7d\", \"12h\"]").unwrap();
+
+        assert_eq!(
+            metrics.expiry_windows,
+            vec![
+                Duration::from_secs(12 * 3600),
+                Duration::from_secs(7 * 86400),
+                Duration::from_secs(30 * 86400)
+            ]
+        );
+        assert_eq!(
+            metrics.expiry_windows.iter().map(|window| format_duration(*window)).collect::<Vec<_>>(),
+            ["12h", "7d", "30d"]
+        );
+    }
+
+    #[test]
+    fn expiry_windows_reject_zero_and_invalid_durations() {
+        for expiry_windows in [r#"["0s"]"#, r#"["7d", "soon"]"#] {
+            let result = toml::from_str::<Metrics>(&format!(
+                "prune_interval = \"30m\"\nrefresh_interval = \"1m\"\nexpiry_windows = {expiry_windows}"
+            ));
+            let error = result.unwrap_err().to_string();
+            assert!(error.contains("expiry_windows"), "{expiry_windows}: {error}");
+        }
+    }
 }
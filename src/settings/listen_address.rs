@@ -0,0 +1,99 @@
+/*
+ * Licensed to the Apache Software Foundation (ASF) under one
+ * or more contributor license agreements.  See the NOTICE file
+ * distributed with this work for additional information
+ * regarding copyright ownership.  The ASF licenses this file
+ * to you under the Apache License, Version 2.0 (the
+ * "License"); you may not use this file except in compliance
+ * with the License.  You may obtain a copy of the License at
+ *
+ *   http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing,
+ * software distributed under the License is distributed on an
+ * "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+ * KIND, either express or implied.  See the License for the
+ * specific language governing permissions and limitations
+ * under the License.
+ */
+
+use std::{fmt, net::SocketAddr, path::PathBuf, str::FromStr};
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+/// Where a listener accepts connections, either a TCP socket address like `0.0.0.0:9081`
+/// or the path of a Unix domain socket prefixed with `unix:`, like `unix:/run/azure_app_exporter.sock`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ListenAddress {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+impl FromStr for ListenAddress {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.strip_prefix("unix:") {
+            Some("") => Err("unix socket path cannot be empty".into()),
+            Some(path) => Ok(Self::Unix(path.into())),
+            None => s
+                .parse()
+                .map(Self::Tcp)
+                .map_err(|e| format!(r#"invalid listen address "{s}", expected an ip:port or unix:/path/to/socket: {e}"#)),
+        }
+    }
+}
+
+impl fmt::Display for ListenAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Tcp(address) => address.fmt(f),
+            Self::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+impl Serialize for ListenAddress {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for ListenAddress {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_listen_addresses() {
+        let cases = [
+            ("0.0.0.0:9081", Ok(ListenAddress::Tcp("0.0.0.0:9081".parse().unwrap()))),
+            ("[::1]:443", Ok(ListenAddress::Tcp("[::1]:443".parse().unwrap()))),
+            (
+                "unix:/run/azure_app_exporter.sock",
+                Ok(ListenAddress::Unix("/run/azure_app_exporter.sock".into())),
+            ),
+            ("unix:relative.sock", Ok(ListenAddress::Unix("relative.sock".into()))),
+            ("unix:", Err("unix socket path cannot be empty")),
+            ("localhost:9081", Err("invalid listen address")),
+            ("0.0.0.0", Err("invalid listen address")),
+            ("/run/azure_app_exporter.sock", Err("invalid listen address")),
+        ];
+
+        for (s, expected) in cases {
+            match (s.parse::<ListenAddress>(), expected) {
+                (Ok(address), Ok(expected)) => {
+                    assert_eq!(address, expected);
+                    assert_eq!(address.to_string(), s);
+                }
+                (Err(error), Err(expected)) => assert!(error.starts_with(expected), "{s}: {error}"),
+                (result, expected) => panic!("{s}: expected {expected:?}, got {result:?}"),
+            }
+        }
+    }
+}
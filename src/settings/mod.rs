@@ -19,4 +19,5 @@
 
 pub mod app_settings;
 pub mod args;
+pub mod listen_address;
 pub mod tls_parser;
@@ -19,49 +19,137 @@
 
 use std::time::{Duration, Instant};
 
-use serde::Deserialize;
+use serde::{Deserialize, Deserializer};
 
-use crate::{app_metrics::TOKEN_SECONDS, global_state::GlobalState};
+use crate::{
+    app_metrics::TOKEN_SECONDS,
+    global_state::{GlobalState, TenantState},
+    settings::app_settings::{Credentials, TokenSource},
+    tasks::{get_azure_json, AzureError},
+    utils::ClientCertificate,
+};
 
 #[derive(Debug, Deserialize)]
 struct AuthToken {
+    #[serde(deserialize_with = "de_expires_in")]
     expires_in: u64,
     access_token: String,
 }
 
+/// The managed identity endpoint returns "expires_in" as a string instead of a number
+fn de_expires_in<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u64, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum NumberOrString {
+        Number(u64),
+        String(String),
+    }
+
+    match NumberOrString::deserialize(deserializer)? {
+        NumberOrString::Number(n) => Ok(n),
+        NumberOrString::String(s) => s.parse().map_err(serde::de::Error::custom),
+    }
+}
+
 /// https://learn.microsoft.com/en-us/graph/auth-v2-service#4-request-an-access-token
-pub async fn azure_api_token_updater(global_state: &GlobalState) {
-    let inner = || async move {
-        let url = format!(
-            "https://login.microsoftonline.com/{}/oauth2/v2.0/token",
-            global_state.settings.credentials.tenant_id
-        );
-        tracing::debug!(url, "getting azure api token with client id and secret");
-
-        let response: AuthToken = global_state
-            .http_client
-            .post(url)
-            .form(&[
-                ("grant_type", "client_credentials"),
-                ("scope", "https://graph.microsoft.com/.default"),
-                ("client_id", &global_state.settings.credentials.client_id),
-                ("client_secret", &global_state.settings.credentials.client_secret),
-            ])
-            .send()
-            .await?
-            .json()
-            .await?;
-
-        let mut azure_api_token = global_state.azure_api_token.write().expect("lock poisoned");
+/// https://learn.microsoft.com/en-us/entra/identity/managed-identities-azure-resources/how-to-use-vm-token#get-a-token-using-http
+pub async fn azure_api_token_updater(global_state: &GlobalState, tenant: &TenantState) {
+    let inner = |credentials: Credentials| async move {
+        let request = if credentials.token_source == TokenSource::ManagedIdentity {
+            tracing::debug!(
+                url = credentials.managed_identity_url,
+                "getting azure api token from managed identity endpoint"
+            );
+
+            global_state
+                .http_client
+                .get(&credentials.managed_identity_url)
+                .header("Metadata", "true")
+                .query(&[
+                    ("api-version", "2018-02-01"),
+                    ("resource", credentials.graph_url.as_str()),
+                    ("client_id", &credentials.client_id),
+                ])
+        } else {
+            let url = format!("{}/{}/oauth2/v2.0/token", credentials.authority_host, credentials.tenant_id);
+
+            let mut form = vec![
+                ("grant_type", "client_credentials".to_string()),
+                ("scope", format!("{}/.default", credentials.graph_url)),
+                ("client_id", credentials.client_id.clone()),
+            ];
+
+            match credentials.token_source {
+                TokenSource::ClientSecret => {
+                    tracing::debug!(url, "getting azure api token with client id and secret");
+
+                    let client_secret = credentials
+                        .client_secret
+                        .clone()
+                        .expect("client secret is validated when parsing settings");
+                    form.push(("client_secret", client_secret));
+                }
+                TokenSource::Certificate => {
+                    tracing::debug!(url, "getting azure api token with client id and certificate");
+
+                    let certificate_file = credentials
+                        .certificate_file
+                        .as_ref()
+                        .expect("certificate file is validated when parsing settings");
+
+                    // Read the certificate on every update so rotated certificates are picked up without restarting
+                    let certificate = ClientCertificate::load(
+                        certificate_file,
+                        credentials.private_key_file.as_deref(),
+                        credentials.certificate_password.as_deref(),
+                    )
+                    .map_err(|e| AzureError::Credentials(format!("failed loading certificate {}: {e}", certificate_file.display())))?;
+                    let client_assertion = certificate
+                        .client_assertion(&credentials.client_id, &url)
+                        .map_err(|e| AzureError::Credentials(format!("failed signing client assertion: {e}")))?;
+
+                    form.push(("client_assertion_type", "urn:ietf:params:oauth:client-assertion-type:jwt-bearer".into()));
+                    form.push(("client_assertion", client_assertion));
+
+                    *tenant.client_certificate.write() = Some(certificate);
+                }
+                TokenSource::WorkloadIdentity => {
+                    tracing::debug!(url, "getting azure api token with client id and federated token");
+
+                    let federated_token_file = credentials
+                        .federated_token_file
+                        .as_ref()
+                        .expect("federated token file is validated when parsing settings");
+
+                    // The federated token is short-lived and rotated by the platform, so it has to be read on every update
+                    let federated_token = std::fs::read_to_string(federated_token_file).map_err(|e| {
+                        AzureError::Credentials(format!("failed reading federated token file {}: {e}", federated_token_file.display()))
+                    })?;
+
+                    form.push(("client_assertion_type", "urn:ietf:params:oauth:client-assertion-type:jwt-bearer".into()));
+                    form.push(("client_assertion", federated_token.trim().to_string()));
+                }
+                TokenSource::ManagedIdentity => unreachable!("managed identity tokens are not requested with the client credentials flow"),
+            }
+
+            global_state.http_client.post(url).form(&form)
+        };
+
+        let response: AuthToken = get_azure_json(request, &global_state.settings().retry, &tenant.tenant_id, "token").await?;
+
+        let mut azure_api_token = tenant.azure_api_token.write();
         *azure_api_token = response.access_token;
 
-        Ok::<_, Box<dyn std::error::Error + Send + Sync>>(response.expires_in)
+        Ok::<_, AzureError>(response.expires_in)
     };
 
     loop {
+        // Get the credentials on every update to pick up reloaded settings
+        let credentials = global_state.tenant_settings(tenant).credentials;
+        let token_source = credentials.token_source;
         let start = Instant::now();
 
-        let result = inner().await;
+        let result = inner(credentials).await;
 
         let elapsed = start.elapsed();
         let took_millis = elapsed.as_millis() as u64;
@@ -71,7 +159,12 @@ pub async fn azure_api_token_updater(global_state: &GlobalState) {
                 let dur = Duration::from_secs(expires_in).mul_f64(0.9); // Sleep for 90% of the token's validity duration
                 let next_update_in_millis = dur.as_millis() as u64;
 
-                tracing::info!(took_millis, next_update_in_millis, "updated azure api token");
+                tracing::info!(
+                    tenant_id = tenant.tenant_id,
+                    took_millis,
+                    next_update_in_millis,
+                    "updated azure api token"
+                );
 
                 (dur, "success")
             }
@@ -79,14 +172,35 @@ pub async fn azure_api_token_updater(global_state: &GlobalState) {
                 let dur = Duration::from_secs(30); // Try again in 30 seconds on error
                 let next_update_in_millis = dur.as_millis() as u64;
 
-                tracing::error!(took_millis, next_update_in_millis, error = e, "failed updating azure api token");
+                // Errors of requests are already counted when they happen, but not errors reading our own credentials
+                if let AzureError::Credentials(_) = e {
+                    e.count(&tenant.tenant_id, "token");
+                }
+
+                tracing::error!(
+                    tenant_id = tenant.tenant_id,
+                    took_millis,
+                    next_update_in_millis,
+                    error_kind = e.kind(),
+                    hint = e.hint(),
+                    error = %e,
+                    "failed updating azure api token"
+                );
 
                 (dur, "fail")
             }
         };
 
-        metrics::histogram!(TOKEN_SECONDS, &[("status", status)]).record(elapsed);
-
-        tokio::time::sleep(sleep_duration).await;
+        let labels = [
+            ("tenant_id", tenant.tenant_id.clone()),
+            ("token_source", token_source.as_str().to_string()),
+            ("status", status.to_string()),
+        ];
+        metrics::histogram!(TOKEN_SECONDS, &labels).record(elapsed);
+
+        tokio::select! {
+            _ = tokio::time::sleep(sleep_duration) => {}
+            _ = tenant.credentials_changed.notified() => {}
+        }
     }
 }
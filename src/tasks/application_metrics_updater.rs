@@ -17,33 +17,693 @@
  * under the License.
  */
 
-use std::time::Duration;
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    time::Duration,
+};
 
-use crate::{app_metrics::APPLICATION_PASSWORD_SECONDS, global_state::GlobalState};
+use chrono::{DateTime, Utc};
+
+use crate::{
+    app_metrics::{
+        APPLICATIONS_CONSECUTIVE_FAILURES, APPLICATIONS_LAST_SUCCESS, APPLICATIONS_WITHOUT_CREDENTIALS, APPLICATION_AGE,
+        APPLICATION_CERTIFICATE_EXPIRY, APPLICATION_CERTIFICATE_SECONDS, APPLICATION_PASSWORD_EXPIRY, APPLICATION_PASSWORD_SECONDS,
+        CLIENT_CERTIFICATE_SECONDS, CREDENTIALS_EXPIRED, CREDENTIALS_EXPIRING, CREDENTIALS_UNDECODABLE, CREDENTIALS_WITHOUT_END_DATE, CREDENTIAL_AGE,
+        CREDENTIAL_INFO, CREDENTIAL_LIFETIME, POLICY_VIOLATION, SERVICE_PRINCIPALS_CONSECUTIVE_FAILURES, SERVICE_PRINCIPALS_LAST_SUCCESS,
+        SERVICE_PRINCIPAL_CERTIFICATE_EXPIRY, SERVICE_PRINCIPAL_CERTIFICATE_SECONDS, SERVICE_PRINCIPAL_PASSWORD_EXPIRY,
+        SERVICE_PRINCIPAL_PASSWORD_SECONDS,
+    },
+    global_state::{CacheStatus, GlobalState, TenantState},
+    settings::app_settings::{format_duration, MetricLabels, Policy},
+    types::{
+        applications::{AzureApplication, KeyCredential, PasswordCredential},
+        lenient::LenientCredential,
+        policies::{self, PolicyViolation},
+    },
+};
 
 pub async fn azure_metrics_updater(global_state: &GlobalState) {
-    while global_state.applications.read().expect("lock poisoned").is_empty() {
+    // Wait for the first refresh instead of for cached objects, since a tenant can have none
+    while global_state.tenants.iter().all(|tenant| {
+        !tenant.applications_status.read().first_attempt_completed()
+            && !tenant.service_principals_status.read().first_attempt_completed()
+            && tenant.client_certificate.read().is_none()
+    }) {
         tokio::time::sleep(Duration::from_secs(7)).await;
     }
 
     loop {
-        for app in global_state.applications.read().expect("lock poisoned").values() {
-            for password in app.password_credentials.iter() {
-                let labels = [
+        for tenant in global_state.tenants.iter() {
+            update_tenant_metrics(global_state, tenant);
+        }
+
+        // Otherwise `/metrics` computes them from the caches on every scrape
+        if !global_state.compute_metrics_on_scrape {
+            for sample in credential_metric_samples(global_state) {
+                metrics::gauge!(sample.name, &sample.labels).set(sample.value);
+            }
+        }
+
+        tokio::time::sleep(global_state.settings().metrics.refresh_interval).await;
+    }
+}
+
+/// A value of a credential gauge with the labels selected in the settings
+pub struct MetricSample {
+    pub name: &'static str,
+    pub labels: Vec<(&'static str, String)>,
+    pub value: f64,
+}
+
+fn update_tenant_metrics(global_state: &GlobalState, tenant: &TenantState) {
+    let tenant_settings = global_state.tenant_settings(tenant);
+
+    // Also set on every metrics refresh instead of only after each cache refresh, so they are not pruned between cache refreshes
+    if tenant_settings.applications.enabled {
+        let status = tenant.applications_status.read();
+        set_cache_status_metrics(tenant, &status, APPLICATIONS_LAST_SUCCESS, APPLICATIONS_CONSECUTIVE_FAILURES);
+    }
+    if tenant_settings.service_principals.enabled {
+        let status = tenant.service_principals_status.read();
+        set_cache_status_metrics(tenant, &status, SERVICE_PRINCIPALS_LAST_SUCCESS, SERVICE_PRINCIPALS_CONSECUTIVE_FAILURES);
+    }
+}
+
+/// Compute the credential gauges of all tenants and the summary gauges from the caches
+pub fn credential_metric_samples(global_state: &GlobalState) -> Vec<MetricSample> {
+    let mut samples: Vec<_> = global_state
+        .tenants
+        .iter()
+        .flat_map(|tenant| tenant_credential_metric_samples(global_state, tenant))
+        .collect();
+    samples.extend(summary_metric_samples(global_state));
+
+    merge_duplicate_samples(samples)
+}
+
+/// Metric name and labels of a series
+type SeriesKey = (&'static str, Vec<(&'static str, String)>);
+
+/// Credentials end up with the same labels if the labels selected in the settings do not identify them, e.g. without the key IDs.
+/// Prometheus rejects a scrape with duplicate series, so they are combined into one: the soonest expiry for the remaining seconds
+/// and expiry timestamps, and the largest value otherwise, e.g. the oldest age
+fn merge_duplicate_samples(samples: Vec<MetricSample>) -> Vec<MetricSample> {
+    const SOONEST_EXPIRY_METRICS: &[&str] = &[
+        APPLICATION_PASSWORD_SECONDS,
+        APPLICATION_CERTIFICATE_SECONDS,
+        SERVICE_PRINCIPAL_PASSWORD_SECONDS,
+        SERVICE_PRINCIPAL_CERTIFICATE_SECONDS,
+        APPLICATION_PASSWORD_EXPIRY,
+        APPLICATION_CERTIFICATE_EXPIRY,
+        SERVICE_PRINCIPAL_PASSWORD_EXPIRY,
+        SERVICE_PRINCIPAL_CERTIFICATE_EXPIRY,
+    ];
+
+    let mut merged: BTreeMap<SeriesKey, f64> = BTreeMap::new();
+    for sample in samples {
+        merged
+            .entry((sample.name, sample.labels))
+            .and_modify(|value| {
+                *value = if SOONEST_EXPIRY_METRICS.contains(&sample.name) {
+                    value.min(sample.value)
+                } else {
+                    value.max(sample.value)
+                }
+            })
+            .or_insert(sample.value);
+    }
+
+    merged
+        .into_iter()
+        .map(|((name, labels), value)| MetricSample { name, labels, value })
+        .collect()
+}
+
+fn tenant_credential_metric_samples(global_state: &GlobalState, tenant: &TenantState) -> Vec<MetricSample> {
+    let tenant_settings = global_state.tenant_settings(tenant);
+    let settings = global_state.settings();
+    let metric_labels = &settings.metrics.labels;
+    let mut samples = Vec::new();
+
+    if let Some(ref certificate) = *tenant.client_certificate.read() {
+        samples.push(MetricSample {
+            name: CLIENT_CERTIFICATE_SECONDS,
+            labels: vec![
+                ("tenant_id", tenant.tenant_id.clone()),
+                ("client_id", tenant_settings.credentials.client_id.clone()),
+                ("certificate_thumbprint", certificate.thumbprint.clone()),
+            ],
+            value: (certificate.not_after - Utc::now()).num_seconds() as f64,
+        });
+    }
+
+    for app in tenant.applications.read().values() {
+        let owner_labels = [
+            ("tenant_id", tenant.tenant_id.clone()),
+            ("id", app.id.clone()),
+            ("app_id", app.app_id.clone()),
+            ("app_display_name", app.display_name.clone().unwrap_or_default()),
+            ("object", "application".into()),
+        ];
+
+        if let Some(created_date_time) = app.created_date_time {
+            samples.push(MetricSample {
+                name: APPLICATION_AGE,
+                labels: select_labels(&owner_labels, &metric_labels.age),
+                value: (Utc::now() - created_date_time).num_seconds() as f64,
+            });
+        }
+
+        // Credentials that failed to decode have no dates to export
+        for password in app.password_credentials.iter().filter(|password| password.decode_error.is_none()) {
+            let labels = [owner_labels.as_slice(), &password_labels(password)].concat();
+            let metrics = (APPLICATION_PASSWORD_SECONDS, APPLICATION_PASSWORD_EXPIRY);
+            push_credential_samples(
+                &mut samples,
+                metrics,
+                &labels,
+                metric_labels,
+                password.remaining_seconds(),
+                (password.start_date_time, password.end_date_time),
+                Utc::now(),
+            );
+        }
+
+        for certificate in app.key_credentials.iter().filter(|certificate| certificate.decode_error.is_none()) {
+            let labels = [owner_labels.as_slice(), &certificate_labels(certificate)].concat();
+            let metrics = (APPLICATION_CERTIFICATE_SECONDS, APPLICATION_CERTIFICATE_EXPIRY);
+            push_credential_samples(
+                &mut samples,
+                metrics,
+                &labels,
+                metric_labels,
+                certificate.remaining_seconds(),
+                (certificate.start_date_time, certificate.end_date_time),
+                Utc::now(),
+            );
+        }
+    }
+
+    samples.extend(policy_violation_samples(
+        &settings.policies,
+        &tenant.tenant_id,
+        &tenant.applications.read(),
+        &tenant.policy_violations.read(),
+    ));
+
+    for service_principal in tenant.service_principals.read().values() {
+        let owner_labels = [
+            ("tenant_id", tenant.tenant_id.clone()),
+            ("id", service_principal.id.clone()),
+            ("app_id", service_principal.app_id.clone()),
+            (
+                "service_principal_display_name",
+                service_principal.display_name.clone().unwrap_or_default(),
+            ),
+            (
+                "service_principal_type",
+                service_principal.service_principal_type.clone().unwrap_or_default(),
+            ),
+            ("object", "service_principal".into()),
+        ];
+
+        for password in service_principal
+            .password_credentials
+            .iter()
+            .filter(|password| password.decode_error.is_none())
+        {
+            let labels = [owner_labels.as_slice(), &password_labels(password)].concat();
+            let metrics = (SERVICE_PRINCIPAL_PASSWORD_SECONDS, SERVICE_PRINCIPAL_PASSWORD_EXPIRY);
+            push_credential_samples(
+                &mut samples,
+                metrics,
+                &labels,
+                metric_labels,
+                password.remaining_seconds(),
+                (password.start_date_time, password.end_date_time),
+                Utc::now(),
+            );
+        }
+
+        for certificate in service_principal
+            .key_credentials
+            .iter()
+            .filter(|certificate| certificate.decode_error.is_none())
+        {
+            // SAML token signing certificates are the key credentials whose thumbprint is the preferred one
+            let preferred_token_signing = certificate
+                .thumbprint()
+                .zip(service_principal.preferred_token_signing_key_thumbprint.as_ref())
+                .is_some_and(|(thumbprint, preferred)| thumbprint.eq_ignore_ascii_case(preferred));
+
+            let labels = [
+                owner_labels.as_slice(),
+                &certificate_labels(certificate),
+                &[("certificate_preferred_token_signing", preferred_token_signing.to_string())],
+            ]
+            .concat();
+            let metrics = (SERVICE_PRINCIPAL_CERTIFICATE_SECONDS, SERVICE_PRINCIPAL_CERTIFICATE_EXPIRY);
+            push_credential_samples(
+                &mut samples,
+                metrics,
+                &labels,
+                metric_labels,
+                certificate.remaining_seconds(),
+                (certificate.start_date_time, certificate.end_date_time),
+                Utc::now(),
+            );
+        }
+    }
+
+    samples
+}
+
+/// 1 for each application violating a rule of a policy and 0 for each other application the policy applies to, so that the series of
+/// a fixed violation drops to 0 instead of staying at 1 until it is pruned. An application violating several rules of a policy is one series
+fn policy_violation_samples(
+    policies: &[Policy],
+    tenant_id: &str,
+    applications: &HashMap<String, AzureApplication>,
+    violations: &[PolicyViolation],
+) -> Vec<MetricSample> {
+    let violating: HashSet<_> = violations
+        .iter()
+        .map(|violation| (violation.policy.as_str(), violation.id.as_str()))
+        .collect();
+    let mut samples = Vec::new();
+
+    for policy in policies {
+        for app in applications.values().filter(|app| policies::applies_to(policy, app)) {
+            samples.push(MetricSample {
+                name: POLICY_VIOLATION,
+                labels: vec![
+                    ("policy", policy.name.clone()),
+                    ("tenant_id", tenant_id.to_string()),
                     ("id", app.id.clone()),
                     ("app_id", app.app_id.clone()),
-                    ("app_display_name", app.display_name.clone().unwrap_or_default()),
-                    ("password_key_id", password.key_id.clone()),
-                    ("password_display_name", password.display_name.clone().unwrap_or_default()),
-                    (
-                        "password_end_date_time",
-                        password.end_date_time.map(|d| d.to_string()).unwrap_or_default(),
-                    ),
-                ];
-                metrics::gauge!(APPLICATION_PASSWORD_SECONDS, &labels).set(password.remaining_seconds());
+                ],
+                value: if violating.contains(&(policy.name.as_str(), app.id.as_str())) {
+                    1.0
+                } else {
+                    0.0
+                },
+            });
+        }
+    }
+
+    samples
+}
+
+/// Add the remaining seconds, expiry timestamp, age and lifetime gauges and the info metric of a credential, each with the labels selected in the settings
+fn push_credential_samples(
+    samples: &mut Vec<MetricSample>,
+    (remaining_seconds_metric, expiry_timestamp_metric): (&'static str, &'static str),
+    labels: &[(&'static str, String)],
+    metric_labels: &MetricLabels,
+    remaining_seconds: f64,
+    (start_date_time, end_date_time): (Option<DateTime<Utc>>, Option<DateTime<Utc>>),
+    now: DateTime<Utc>,
+) {
+    samples.push(MetricSample {
+        name: remaining_seconds_metric,
+        labels: select_labels(labels, &metric_labels.remaining_seconds),
+        value: remaining_seconds,
+    });
+
+    // Credentials without an end date never expire, so they have no expiry timestamp
+    if let Some(end_date_time) = end_date_time {
+        samples.push(MetricSample {
+            name: expiry_timestamp_metric,
+            labels: select_labels(labels, &metric_labels.expiry_timestamp),
+            value: end_date_time.timestamp() as f64,
+        });
+    }
+
+    samples.push(MetricSample {
+        name: CREDENTIAL_INFO,
+        labels: select_labels(labels, &metric_labels.credential_info),
+        value: 1.0,
+    });
+
+    if let Some(start_date_time) = start_date_time {
+        samples.push(MetricSample {
+            name: CREDENTIAL_AGE,
+            labels: select_labels(labels, &metric_labels.age),
+            value: (now - start_date_time).num_seconds() as f64,
+        });
+
+        if let Some(end_date_time) = end_date_time {
+            samples.push(MetricSample {
+                name: CREDENTIAL_LIFETIME,
+                labels: select_labels(labels, &metric_labels.age),
+                value: (end_date_time - start_date_time).num_seconds() as f64,
+            });
+        }
+    }
+}
+
+/// Count the expired, expiring, non-expiring and undecodable credentials and the applications without credentials over all tenants,
+/// partitioned by the labels selected in the settings. Combinations without any credentials are exported as 0
+fn summary_metric_samples(global_state: &GlobalState) -> Vec<MetricSample> {
+    let settings = global_state.settings();
+    let selected = &settings.metrics.labels.summary;
+    let mut counts = SummaryCounts::new();
+    let now = Utc::now();
+
+    for tenant in global_state.tenants.iter() {
+        let tenant_settings = global_state.tenant_settings(tenant);
+        let summary_labels = |object: &str, credential_type: &str| {
+            let labels = [
+                ("tenant_id", tenant.tenant_id.clone()),
+                ("object", object.to_string()),
+                ("credential_type", credential_type.to_string()),
+            ];
+            select_labels(&labels, selected)
+        };
+
+        if tenant_settings.applications.enabled {
+            let applications = tenant.applications.read();
+
+            let passwords = applications.values().flat_map(|app| app.password_credentials.iter());
+            count_credentials(
+                &mut counts,
+                summary_labels("application", "password"),
+                &settings.metrics.expiry_windows,
+                passwords,
+                now,
+            );
+
+            let certificates = applications.values().flat_map(|app| app.key_credentials.iter());
+            count_credentials(
+                &mut counts,
+                summary_labels("application", "certificate"),
+                &settings.metrics.expiry_windows,
+                certificates,
+                now,
+            );
+
+            // Credentials that failed to decode are counted as undecodable, and the application is not without credentials
+            let without_credentials = applications
+                .values()
+                .filter(|app| app.password_credentials.is_empty() && app.key_credentials.is_empty())
+                .count();
+            let labels = select_labels(&[("tenant_id", tenant.tenant_id.clone())], selected);
+            *counts.entry((APPLICATIONS_WITHOUT_CREDENTIALS, labels)).or_default() += without_credentials as f64;
+        }
+
+        if tenant_settings.service_principals.enabled {
+            let service_principals = tenant.service_principals.read();
+
+            let passwords = service_principals
+                .values()
+                .flat_map(|service_principal| service_principal.password_credentials.iter());
+            count_credentials(
+                &mut counts,
+                summary_labels("service_principal", "password"),
+                &settings.metrics.expiry_windows,
+                passwords,
+                now,
+            );
+
+            let certificates = service_principals
+                .values()
+                .flat_map(|service_principal| service_principal.key_credentials.iter());
+            count_credentials(
+                &mut counts,
+                summary_labels("service_principal", "certificate"),
+                &settings.metrics.expiry_windows,
+                certificates,
+                now,
+            );
+        }
+    }
+
+    counts
+        .into_iter()
+        .map(|((name, labels), value)| MetricSample { name, labels, value })
+        .collect()
+}
+
+/// Summary gauge values by metric name and labels. Counts of tenants that end up with the same labels are added up
+type SummaryCounts = BTreeMap<SeriesKey, f64>;
+
+/// Credentials that failed to decode have no dates, so they are only counted as undecodable
+fn count_credentials<'a, T: LenientCredential + 'a>(
+    counts: &mut SummaryCounts,
+    labels: Vec<(&'static str, String)>,
+    expiry_windows: &[Duration],
+    credentials: impl Iterator<Item = &'a T>,
+    now: DateTime<Utc>,
+) {
+    let mut expired = 0;
+    let mut without_end_date = 0;
+    let mut undecodable = 0;
+    let mut expiring = vec![0; expiry_windows.len()];
+
+    for credential in credentials {
+        if credential.decode_error().is_some() {
+            undecodable += 1;
+            continue;
+        }
+
+        match credential.end_date_time() {
+            None => without_end_date += 1,
+            Some(end_date_time) if end_date_time <= now => expired += 1,
+            Some(end_date_time) => {
+                // The windows are sorted, so a credential expiring within one window also counts for all larger ones
+                let remaining = (end_date_time - now).to_std().unwrap_or_default();
+                for (count, window) in expiring.iter_mut().zip(expiry_windows) {
+                    if remaining <= *window {
+                        *count += 1;
+                    }
+                }
             }
         }
+    }
+
+    *counts.entry((CREDENTIALS_EXPIRED, labels.clone())).or_default() += expired as f64;
+    *counts.entry((CREDENTIALS_WITHOUT_END_DATE, labels.clone())).or_default() += without_end_date as f64;
+    *counts.entry((CREDENTIALS_UNDECODABLE, labels.clone())).or_default() += undecodable as f64;
+    for (count, window) in expiring.into_iter().zip(expiry_windows) {
+        let labels = [labels.as_slice(), &[("window", format_duration(*window))]].concat();
+        *counts.entry((CREDENTIALS_EXPIRING, labels)).or_default() += count as f64;
+    }
+}
+
+fn select_labels(labels: &[(&'static str, String)], selected: &[String]) -> Vec<(&'static str, String)> {
+    labels.iter().filter(|(name, _)| selected.iter().any(|s| s == name)).cloned().collect()
+}
+
+fn set_cache_status_metrics(
+    tenant: &TenantState,
+    status: &CacheStatus,
+    last_success_metric: &'static str,
+    consecutive_failures_metric: &'static str,
+) {
+    let labels = [("tenant_id", tenant.tenant_id.clone())];
+
+    if let Some(last_success) = status.last_success {
+        metrics::gauge!(last_success_metric, &labels).set(last_success.timestamp() as f64);
+    }
+    metrics::gauge!(consecutive_failures_metric, &labels).set(status.consecutive_failures as f64);
+}
+
+fn password_labels(password: &PasswordCredential) -> [(&'static str, String); 4] {
+    [
+        ("credential_type", "password".into()),
+        ("password_key_id", password.key_id.clone()),
+        ("password_display_name", password.display_name.clone().unwrap_or_default()),
+        (
+            "password_end_date_time",
+            password.end_date_time.map(|d| d.to_string()).unwrap_or_default(),
+        ),
+    ]
+}
+
+fn certificate_labels(certificate: &KeyCredential) -> [(&'static str, String); 7] {
+    [
+        ("credential_type", "certificate".into()),
+        ("certificate_key_id", certificate.key_id.clone()),
+        ("certificate_display_name", certificate.display_name.clone().unwrap_or_default()),
+        ("certificate_type", certificate.key_type.clone().unwrap_or_default()),
+        ("certificate_usage", certificate.usage.clone().unwrap_or_default()),
+        ("certificate_thumbprint", certificate.thumbprint().unwrap_or_default()),
+        (
+            "certificate_end_date_time",
+            certificate.end_date_time.map(|d| d.to_string()).unwrap_or_default(),
+        ),
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn date(date: &str) -> DateTime<Utc> {
+        date.parse().unwrap()
+    }
+
+    #[test]
+    fn credential_age_and_lifetime_from_start_and_end_date() {
+        let labels = [
+            ("tenant_id", "t1".to_string()),
+            ("id", "id1".into()),
+            ("app_display_name", "app".into()),
+            ("object", "application".into()),
+            ("credential_type", "password".into()),
+            ("password_key_id", "key1".into()),
+        ];
+        let mut samples = Vec::new();
+        push_credential_samples(
+            &mut samples,
+            (APPLICATION_PASSWORD_SECONDS, APPLICATION_PASSWORD_EXPIRY),
+            &labels,
+            &MetricLabels::default(),
+            0.0,
+            (Some(date("2024-01-01T00:00:00Z")), Some(date("2024-01-31T00:00:00Z"))),
+            date("2024-01-11T00:00:00Z"),
+        );
+
+        let sample = |name| samples.iter().find(|sample| sample.name == name).unwrap();
+        assert_eq!(sample(CREDENTIAL_AGE).value, 10.0 * 86400.0);
+        assert_eq!(sample(CREDENTIAL_LIFETIME).value, 30.0 * 86400.0);
+        assert_eq!(sample(APPLICATION_PASSWORD_EXPIRY).value, date("2024-01-31T00:00:00Z").timestamp() as f64);
+
+        let expected_labels = vec![
+            ("tenant_id", "t1".to_string()),
+            ("id", "id1".into()),
+            ("object", "application".into()),
+            ("credential_type", "password".into()),
+            ("password_key_id", "key1".into()),
+        ];
+        assert_eq!(sample(CREDENTIAL_AGE).labels, expected_labels);
+        assert_eq!(sample(CREDENTIAL_LIFETIME).labels, expected_labels);
+    }
+
+    #[test]
+    fn credential_without_start_date_has_no_age_or_lifetime() {
+        let mut samples = Vec::new();
+        push_credential_samples(
+            &mut samples,
+            (APPLICATION_PASSWORD_SECONDS, APPLICATION_PASSWORD_EXPIRY),
+            &[("tenant_id", "t1".to_string())],
+            &MetricLabels::default(),
+            0.0,
+            (None, Some(date("2024-01-31T00:00:00Z"))),
+            date("2024-01-11T00:00:00Z"),
+        );
+
+        assert!(samples
+            .iter()
+            .all(|sample| sample.name != CREDENTIAL_AGE && sample.name != CREDENTIAL_LIFETIME));
+    }
+
+    #[test]
+    fn count_credentials_by_expiry() {
+        let now = date("2024-01-01T00:00:00Z");
+        let day = chrono::Duration::days(1);
+        let windows = [Duration::from_secs(7 * 86400), Duration::from_secs(30 * 86400)];
+        let end_date_times = [
+            Some(now - day),
+            Some(now),
+            Some(now + day),
+            Some(now + day * 7),
+            Some(now + day * 8),
+            Some(now + day * 31),
+            None,
+        ];
+        let mut passwords = end_date_times
+            .into_iter()
+            .map(|end_date_time| PasswordCredential {
+                key_id: "key1".into(),
+                display_name: None,
+                start_date_time: None,
+                end_date_time,
+                decode_error: None,
+            })
+            .collect::<Vec<_>>();
+        passwords.push(PasswordCredential::invalid(&serde_json::json!({"keyId": "key2"}), "invalid time".into()));
+
+        let mut counts = SummaryCounts::new();
+        let labels = vec![("tenant_id", "t1".to_string())];
+        count_credentials(&mut counts, labels.clone(), &windows, passwords.iter(), now);
+
+        let count = |name, window: Option<&str>| {
+            let mut labels = labels.clone();
+            labels.extend(window.map(|window| ("window", window.to_string())));
+            counts[&(name, labels)]
+        };
+        assert_eq!(count(CREDENTIALS_EXPIRED, None), 2.0);
+        assert_eq!(count(CREDENTIALS_WITHOUT_END_DATE, None), 1.0);
+        assert_eq!(count(CREDENTIALS_UNDECODABLE, None), 1.0);
+        assert_eq!(count(CREDENTIALS_EXPIRING, Some("7d")), 2.0);
+        assert_eq!(count(CREDENTIALS_EXPIRING, Some("30d")), 3.0);
+        assert_eq!(counts.len(), 5);
+    }
+
+    #[test]
+    fn resolved_policy_violation_drops_to_zero() {
+        let policies: Vec<Policy> = vec![toml::from_str("name = \"certificates-only\"\nrequire_certificates = true").unwrap()];
+        let applications = |credentials: serde_json::Value| -> HashMap<String, AzureApplication> {
+            let mut app = serde_json::json!({"id": "id1", "appId": "app1", "passwordCredentials": []});
+            app.as_object_mut().unwrap().extend(credentials.as_object().unwrap().clone());
+            HashMap::from([("id1".to_string(), serde_json::from_value(app).unwrap())])
+        };
+        let now = Utc::now();
+        let values = |applications: &HashMap<String, AzureApplication>| {
+            let violations = policies::evaluate_policies(&policies, "t1", applications, now);
+            policy_violation_samples(&policies, "t1", applications, &violations)
+                .into_iter()
+                .map(|sample| (sample.labels, sample.value))
+                .collect::<Vec<_>>()
+        };
+        let labels = vec![
+            ("policy", "certificates-only".to_string()),
+            ("tenant_id", "t1".into()),
+            ("id", "id1".into()),
+            ("app_id", "app1".into()),
+        ];
+
+        let violating = applications(serde_json::json!({"passwordCredentials": [{"keyId": "key1", "endDateTime": null}]}));
+        assert_eq!(values(&violating), vec![(labels.clone(), 1.0)]);
+
+        let fixed = applications(serde_json::json!({"keyCredentials": [{"keyId": "key2", "endDateTime": null}]}));
+        assert_eq!(values(&fixed), vec![(labels, 0.0)]);
+    }
+
+    #[test]
+    fn credentials_with_the_same_selected_labels_are_merged() {
+        let metric_labels = MetricLabels {
+            remaining_seconds: vec!["tenant_id".into(), "id".into()],
+            expiry_timestamp: vec!["tenant_id".into(), "id".into()],
+            ..Default::default()
+        };
+        let now = date("2024-01-11T00:00:00Z");
+        let mut samples = Vec::new();
+        for (key_id, remaining_seconds, end_date_time) in [("key1", 200.0, "2024-02-01T00:00:00Z"), ("key2", 100.0, "2024-01-21T00:00:00Z")] {
+            let labels = [("tenant_id", "t1".to_string()), ("id", "id1".into()), ("password_key_id", key_id.into())];
+            push_credential_samples(
+                &mut samples,
+                (APPLICATION_PASSWORD_SECONDS, APPLICATION_PASSWORD_EXPIRY),
+                &labels,
+                &metric_labels,
+                remaining_seconds,
+                (Some(date("2024-01-01T00:00:00Z")), Some(date(end_date_time))),
+                now,
+            );
+        }
+
+        let samples = merge_duplicate_samples(samples);
 
-        tokio::time::sleep(global_state.settings.metrics.refresh_interval).await;
+        let values = |name| {
+            samples
+                .iter()
+                .filter(|sample| sample.name == name)
+                .map(|sample| sample.value)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(values(APPLICATION_PASSWORD_SECONDS), [100.0]);
+        assert_eq!(values(APPLICATION_PASSWORD_EXPIRY), [date("2024-01-21T00:00:00Z").timestamp() as f64]);
+        assert_eq!(values(CREDENTIAL_LIFETIME), [31.0 * 86400.0, 20.0 * 86400.0]);
     }
 }
@@ -17,37 +17,178 @@
  * under the License.
  */
 
-use std::time::{Duration, Instant};
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
 
-use crate::{app_metrics::APPLICATIONS_SECONDS, global_state::GlobalState, types::applications::AzureApplications};
+use chrono::Utc;
+use reqwest::StatusCode;
+
+use crate::{
+    app_metrics::{APPLICATIONS_DELTA_CHANGES_TOTAL, APPLICATIONS_SECONDS},
+    global_state::{GlobalState, TenantState},
+    tasks::{get_azure_json, record_credential_decode_errors, record_decode_failures, AzureError, GraphError},
+    types::{
+        applications::{AzureApplicationDelta, AzureApplications, AzureApplicationsDelta},
+        lenient, policies,
+    },
+    utils::send_with_retries,
+};
+
+const APPLICATION_FIELDS: &str = "id,appId,displayName,createdDateTime,passwordCredentials,keyCredentials";
 
 /// https://learn.microsoft.com/en-us/graph/query-parameters
 /// https://learn.microsoft.com/en-us/graph/api/application-list?view=graph-rest-1.0
-pub async fn azure_applications_updater(global_state: &GlobalState) {
+/// https://learn.microsoft.com/en-us/graph/delta-query-overview
+pub async fn azure_applications_updater(global_state: &GlobalState, tenant: &TenantState) {
     // This fn is spawned in a thread simultaneously with another thread
     // responsible for updating the api token, so we should wait for it to finish
-    while global_state.azure_api_token.read().expect("lock poisoned").is_empty() {
-        tracing::warn!("azure api token not yet acquired, sleeping 5 seconds");
+    while tenant.azure_api_token.read().is_empty() {
+        tracing::warn!(tenant_id = tenant.tenant_id, "azure api token not yet acquired, sleeping 5 seconds");
         tokio::time::sleep(Duration::from_secs(5)).await;
     }
 
+    loop {
+        // Get the settings on every refresh to pick up reloaded settings
+        let applications_settings = global_state.tenant_settings(tenant).applications;
+        let start = Instant::now();
+
+        let delta_link = if applications_settings.delta_query {
+            tenant.applications_delta_link.read().clone()
+        } else {
+            None
+        };
+        let attempted_sync_kind = if delta_link.is_some() { "delta" } else { "full" };
+
+        let result = sync_applications(global_state, tenant, delta_link).await;
+
+        let elapsed = start.elapsed();
+        let took_millis = elapsed.as_millis() as u64;
+        let next_update_in_millis = applications_settings.cache_refresh_interval.as_millis() as u64;
+
+        let applications_cached = tenant.applications.read().len();
+
+        let (status_label, sync_kind) = match result {
+            Ok(sync_kind) => {
+                tenant.applications_status.write().record_success();
+
+                tracing::info!(
+                    tenant_id = tenant.tenant_id,
+                    took_millis,
+                    next_update_in_millis,
+                    applications_cached,
+                    sync_kind,
+                    "updated azure applications"
+                );
+
+                ("success", sync_kind)
+            }
+            Err(e) => {
+                let mut applications_status = tenant.applications_status.write();
+                applications_status.record_failure();
+
+                // The cache keeps the applications of the last successful refresh
+                tracing::error!(
+                    tenant_id = tenant.tenant_id,
+                    took_millis,
+                    next_update_in_millis,
+                    applications_cached,
+                    consecutive_failures = applications_status.consecutive_failures,
+                    sync_kind = attempted_sync_kind,
+                    error_kind = e.kind(),
+                    hint = e.hint(),
+                    error = %e,
+                    "failed updating azure applications"
+                );
+
+                ("fail", attempted_sync_kind)
+            }
+        };
+
+        // Also after a failed refresh, since whether a credential is active or expired changes over time
+        update_policy_violations(global_state, tenant);
+
+        metrics::histogram!(
+            APPLICATIONS_SECONDS,
+            &[
+                ("tenant_id", tenant.tenant_id.clone()),
+                ("status", status_label.to_string()),
+                ("sync", sync_kind.to_string())
+            ]
+        )
+        .record(elapsed);
+
+        tokio::time::sleep(applications_settings.cache_refresh_interval).await
+    }
+}
+
+/// Refresh the applications cache of the tenant with a full sync, or a delta sync if a delta link is given.
+/// Falls back to a full sync if the delta link expired. Returns the kind of sync that updated the cache
+async fn sync_applications(global_state: &GlobalState, tenant: &TenantState, delta_link: Option<String>) -> Result<&'static str, AzureError> {
     let get_applications = |url| async move {
-        tracing::debug!(url, "getting azure applications with api token");
+        tracing::debug!(tenant_id = tenant.tenant_id, url, "getting azure applications with api token");
+
+        let request = global_state.http_client.get(url).bearer_auth(tenant.azure_api_token.read());
+
+        get_azure_json::<AzureApplications>(request, &global_state.settings().retry, &tenant.tenant_id, "applications").await
+    };
 
-        global_state
+    // Returns `None` if the delta link expired, in which case we need a full sync to get a new one
+    let get_applications_delta = |url| async move {
+        let applications_settings = global_state.tenant_settings(tenant).applications;
+        tracing::debug!(tenant_id = tenant.tenant_id, url, "getting azure applications delta with api token");
+
+        let request = global_state
             .http_client
             .get(url)
-            .bearer_auth(global_state.azure_api_token.read().expect("lock poisoned"))
-            .send()
-            .await?
-            .json::<AzureApplications>()
+            .bearer_auth(tenant.azure_api_token.read())
+            .header("Prefer", format!("odata.maxpagesize={}", applications_settings.results_per_page));
+
+        let result = async {
+            let response = send_with_retries(request, &global_state.settings().retry, &tenant.tenant_id, "applications_delta").await?;
+
+            if response.status() == StatusCode::GONE {
+                return Ok(None);
+            }
+
+            Ok(Some(AzureError::check_response(response).await?.json::<AzureApplicationsDelta>().await?))
+        };
+
+        result
             .await
+            .inspect_err(|e: &AzureError| e.count(&tenant.tenant_id, "applications_delta"))
+    };
+
+    // Follow the next links until the last page, which has the delta link for the next refresh
+    let get_all_applications_delta = |url| async move {
+        let mut changes = Vec::new();
+        let mut url = url;
+
+        loop {
+            let Some(mut response) = get_applications_delta(url).await? else {
+                return Ok(None);
+            };
+
+            changes.append(&mut response.value);
+
+            match (response.next_link, response.delta_link) {
+                (Some(next_link), _) => url = next_link,
+                (None, Some(delta_link)) => return Ok(Some((changes, delta_link))),
+                (None, None) => {
+                    return Err(AzureError::MalformedPayload(
+                        "azure applications delta response has neither a next link nor a delta link".into(),
+                    ))
+                }
+            }
+        }
     };
 
-    let inner = || async move {
+    let full_sync = || async move {
+        let applications_settings = global_state.tenant_settings(tenant).applications;
         let mut response = get_applications(format!(
-            "{}?$top={}&$select=id,appId,displayName,createdDateTime,passwordCredentials",
-            global_state.settings.applications.url, global_state.settings.applications.results_per_page
+            "{}?$top={}&$select={APPLICATION_FIELDS}",
+            applications_settings.url, applications_settings.results_per_page
         ))
         .await?;
 
@@ -58,47 +199,216 @@ pub async fn azure_applications_updater(global_state: &GlobalState) {
             response.value.append(&mut next_response.value);
         }
 
-        let parsed_applications = response.value.into_iter().map(|application| (application.id.clone(), application));
+        let (parsed_applications, decode_failures) = lenient::partition(response.value);
+
+        record_decode_failures(&tenant.tenant_id, "application", &decode_failures);
+        for application in parsed_applications.iter() {
+            record_credential_decode_errors(
+                &tenant.tenant_id,
+                "password_credential",
+                &application.id,
+                &application.password_credentials,
+            );
+            record_credential_decode_errors(&tenant.tenant_id, "key_credential", &application.id, &application.key_credentials);
+        }
 
-        let mut applications = global_state.applications.write().expect("lock poisoned");
+        let mut applications = tenant.applications.write();
         applications.clear();
-        applications.extend(parsed_applications);
+        applications.extend(parsed_applications.into_iter().map(|application| (application.id.clone(), application)));
+        *tenant.application_decode_failures.write() = decode_failures;
 
-        Ok::<_, Box<dyn std::error::Error + Send + Sync>>(())
+        Ok::<_, AzureError>(())
     };
 
-    loop {
-        let start = Instant::now();
+    // Changed applications that fail to decode are skipped, keeping the previously cached version if any
+    let decode_changes = |changes| {
+        let (changes, decode_failures) = lenient::partition::<AzureApplicationDelta>(changes);
 
-        let result = inner().await;
+        record_decode_failures(&tenant.tenant_id, "application", &decode_failures);
+        for change in changes.iter() {
+            if let Some(password_credentials) = &change.password_credentials {
+                record_credential_decode_errors(&tenant.tenant_id, "password_credential", &change.id, password_credentials);
+            }
+            if let Some(key_credentials) = &change.key_credentials {
+                record_credential_decode_errors(&tenant.tenant_id, "key_credential", &change.id, key_credentials);
+            }
+        }
 
-        let elapsed = start.elapsed();
-        let took_millis = elapsed.as_millis() as u64;
-        let next_update_in_millis = global_state.settings.applications.cache_refresh_interval.as_millis() as u64;
+        (changes, decode_failures)
+    };
+
+    // The first delta query without a delta link returns all applications, just like a full sync
+    let full_delta_sync = || async move {
+        let applications_settings = global_state.tenant_settings(tenant).applications;
+        let (changes, delta_link) = get_all_applications_delta(format!("{}/delta?$select={APPLICATION_FIELDS}", applications_settings.url))
+            .await?
+            .ok_or_else(|| {
+                AzureError::Http(GraphError {
+                    status: StatusCode::GONE,
+                    code: None,
+                    message: Some("azure applications delta query without a delta link responded with 410 Gone".into()),
+                })
+            })?;
+        let (changes, decode_failures) = decode_changes(changes);
+
+        let mut parsed_applications = HashMap::new();
+        for change in changes {
+            change.apply(&mut parsed_applications);
+        }
+
+        *tenant.applications.write() = parsed_applications;
+        *tenant.application_decode_failures.write() = decode_failures;
+        *tenant.applications_delta_link.write() = Some(delta_link);
 
-        let applications_cached = global_state.applications.read().expect("lock poisoned").len();
+        Ok::<_, AzureError>(())
+    };
+
+    // Returns `false` if the delta link expired and nothing was updated
+    let delta_sync = |delta_link| async move {
+        let Some((changes, delta_link)) = get_all_applications_delta(delta_link).await? else {
+            return Ok(false);
+        };
+        let (changes, decode_failures) = decode_changes(changes);
+
+        // Forget earlier failures of the applications that changed since, whether they decode now or not
+        let mut application_decode_failures = tenant.application_decode_failures.write();
+        application_decode_failures.retain(|failure| {
+            failure.id.as_ref().is_some_and(|id| {
+                !changes.iter().any(|change| &change.id == id) && !decode_failures.iter().any(|new_failure| new_failure.id.as_ref() == Some(id))
+            })
+        });
+        application_decode_failures.extend(decode_failures);
+        drop(application_decode_failures);
+
+        let removed = changes.iter().filter(|change| change.removed.is_some()).count();
+        let upserted = changes.len() - removed;
+
+        let mut applications = tenant.applications.write();
+        for change in changes {
+            change.apply(&mut applications);
+        }
+        *tenant.applications_delta_link.write() = Some(delta_link);
+
+        for (change, count) in [("upserted", upserted), ("removed", removed)] {
+            metrics::counter!(
+                APPLICATIONS_DELTA_CHANGES_TOTAL,
+                &[("tenant_id", tenant.tenant_id.clone()), ("change", change.to_string())]
+            )
+            .increment(count as u64);
+        }
 
-        let status_label = match result {
-            Ok(_) => {
-                tracing::info!(took_millis, next_update_in_millis, applications_cached, "updated azure applications");
+        Ok::<_, AzureError>(true)
+    };
 
-                "success"
+    match delta_link {
+        Some(delta_link) => {
+            if delta_sync(delta_link).await? {
+                return Ok("delta");
             }
-            Err(e) => {
-                tracing::error!(
-                    took_millis,
-                    next_update_in_millis,
-                    applications_cached,
-                    error = e,
-                    "failed updating azure applications"
-                );
 
-                "fail"
+            tracing::warn!(
+                tenant_id = tenant.tenant_id,
+                "azure applications delta link expired, falling back to full sync"
+            );
+            *tenant.applications_delta_link.write() = None;
+
+            full_delta_sync().await.map(|_| "full")
+        }
+        None if global_state.tenant_settings(tenant).applications.delta_query => full_delta_sync().await.map(|_| "full"),
+        None => full_sync().await.map(|_| "full"),
+    }
+}
+
+/// Check the cached applications of the tenant against the current `[[policies]]` settings
+pub fn update_policy_violations(global_state: &GlobalState, tenant: &TenantState) {
+    let policy_violations = policies::evaluate_policies(
+        &global_state.settings().policies,
+        &tenant.tenant_id,
+        &tenant.applications.read(),
+        Utc::now(),
+    );
+    *tenant.policy_violations.write() = policy_violations;
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{BufRead, BufReader, Write},
+        net::TcpListener,
+    };
+
+    use super::*;
+
+    /// Answer each request with the status and body of the first target prefix it matches, closing the connection after each response
+    fn serve(listener: TcpListener, responses: Vec<(String, u16, String)>) {
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut reader = BufReader::new(&stream);
+                let mut request_line = String::new();
+                reader.read_line(&mut request_line).unwrap();
+                let mut header = String::new();
+                while reader.read_line(&mut header).unwrap() > 2 {
+                    header.clear();
+                }
+
+                let target = request_line.split(' ').nth(1).unwrap_or_default();
+                let (status, body) = responses
+                    .iter()
+                    .find(|(prefix, _, _)| target.starts_with(prefix.as_str()))
+                    .map(|(_, status, body)| (*status, body.clone()))
+                    .unwrap_or((404, "{}".into()));
+
+                write!(
+                    stream,
+                    "HTTP/1.1 {status} Status\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+                    body.len()
+                )
+                .unwrap();
             }
-        };
+        });
+    }
+
+    #[tokio::test]
+    async fn expired_delta_link_falls_back_to_full_sync() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/v1.0/applications", listener.local_addr().unwrap());
+        let new_application = serde_json::json!({"id": "id2", "appId": "app2", "passwordCredentials": []});
+        serve(
+            listener,
+            vec![
+                (
+                    "/v1.0/applications/delta?token=expired".into(),
+                    410,
+                    r#"{"error": {"code": "syncStateNotFound", "message": "gone"}}"#.into(),
+                ),
+                (
+                    "/v1.0/applications/delta?".into(),
+                    200,
+                    serde_json::json!({"value": [new_application], "@odata.deltaLink": format!("{url}/delta?token=new")}).to_string(),
+                ),
+            ],
+        );
 
-        metrics::histogram!(APPLICATIONS_SECONDS, &[("status", status_label)]).record(elapsed);
+        let global_state = GlobalState::from_toml(&format!(
+            "[[tenants]]\ntenant_id = \"t1\"\nclient_id = \"c1\"\ntoken_source = \"managed_identity\"\n\
+             [tenants.applications]\nurl = \"{url}\"\ndelta_query = true\n"
+        ));
+        let tenant = &global_state.tenants[0];
+
+        let cached_application = serde_json::json!({"id": "id1", "appId": "app1", "passwordCredentials": []});
+        tenant
+            .applications
+            .write()
+            .insert("id1".into(), serde_json::from_value(cached_application).unwrap());
+
+        let sync_kind = sync_applications(&global_state, tenant, Some(format!("{url}/delta?token=expired")))
+            .await
+            .unwrap();
 
-        tokio::time::sleep(global_state.settings.applications.cache_refresh_interval).await
+        assert_eq!(sync_kind, "full");
+        let applications = tenant.applications.read();
+        assert_eq!(applications.keys().collect::<Vec<_>>(), vec!["id2"]);
+        assert_eq!(*tenant.applications_delta_link.read(), Some(format!("{url}/delta?token=new")));
     }
 }
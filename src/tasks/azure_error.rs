@@ -0,0 +1,403 @@
+/*
+ * Licensed to the Apache Software Foundation (ASF) under one
+ * or more contributor license agreements.  See the NOTICE file
+ * distributed with this work for additional information
+ * regarding copyright ownership.  The ASF licenses this file
+ * to you under the Apache License, Version 2.0 (the
+ * "License"); you may not use this file except in compliance
+ * with the License.  You may obtain a copy of the License at
+ *
+ *   http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing,
+ * software distributed under the License is distributed on an
+ * "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+ * KIND, either express or implied.  See the License for the
+ * specific language governing permissions and limitations
+ * under the License.
+ */
+
+//! Errors of requests to Azure, classified by what went wrong so that they can be counted by kind
+//! and logged with a hint on how to fix the most common ones.
+
+use std::fmt::{self, Display};
+
+use reqwest::{RequestBuilder, Response, StatusCode};
+use serde::de::DeserializeOwned;
+
+use crate::{
+    app_metrics::{AZURE_DECODE_ERRORS_TOTAL, AZURE_ERRORS_TOTAL},
+    settings::app_settings::Retry,
+    types::lenient::{DecodeFailure, LenientCredential},
+    utils::send_with_retries,
+};
+
+#[derive(Debug)]
+pub enum AzureError {
+    /// The credentials of the exporter were rejected, e.g. a 401 status or an invalid client on the token endpoint
+    Auth(GraphError),
+    /// The exporter is missing a permission, i.e. a 403 status
+    Forbidden(GraphError),
+    /// A 429 or 503 status, even after retrying
+    Throttled(GraphError),
+    /// Any other error status
+    Http(GraphError),
+    Timeout(reqwest::Error),
+    Network(reqwest::Error),
+    /// The response body is not what we expected
+    MalformedPayload(String),
+    /// Reading the exporter's own credentials failed, e.g. the certificate or federated token file
+    Credentials(String),
+}
+
+/// Error status and the error details from the response body, if any.
+///
+/// Microsoft Graph responds with `{"error": {"code": "...", "message": "..."}}` and
+/// the token endpoint with `{"error": "...", "error_description": "..."}`
+#[derive(Debug)]
+pub struct GraphError {
+    pub status: StatusCode,
+    pub code: Option<String>,
+    pub message: Option<String>,
+}
+
+impl AzureError {
+    /// Label value for the kind of error in metrics and logs
+    pub fn kind(&self) -> &'static str {
+        match self {
+            AzureError::Auth(_) => "auth",
+            AzureError::Forbidden(_) => "forbidden",
+            AzureError::Throttled(_) => "throttled",
+            AzureError::Http(_) => "http",
+            AzureError::Timeout(_) => "timeout",
+            AzureError::Network(_) => "network",
+            AzureError::MalformedPayload(_) => "malformed_payload",
+            AzureError::Credentials(_) => "credentials",
+        }
+    }
+
+    pub fn status(&self) -> Option<StatusCode> {
+        match self {
+            AzureError::Auth(e) | AzureError::Forbidden(e) | AzureError::Throttled(e) | AzureError::Http(e) => Some(e.status),
+            _ => None,
+        }
+    }
+
+    /// What to do about known errors
+    ///
+    /// https://learn.microsoft.com/en-us/entra/identity-platform/reference-error-codes
+    /// https://learn.microsoft.com/en-us/graph/errors
+    pub fn hint(&self) -> Option<&'static str> {
+        let graph_error = match self {
+            AzureError::Auth(e) | AzureError::Forbidden(e) | AzureError::Http(e) => e,
+            AzureError::Throttled(_) => return Some("reduce how often the caches are refreshed or enable delta_query for applications"),
+            _ => return None,
+        };
+
+        // Token endpoint errors have the AADSTS code at the start of their description
+        let message = graph_error.message.as_deref().unwrap_or_default();
+        let code = graph_error.code.as_deref().unwrap_or_default();
+
+        if message.starts_with("AADSTS7000215") {
+            Some("the client secret is invalid, make sure client_secret is the secret value and not the secret ID")
+        } else if message.starts_with("AADSTS7000222") {
+            Some("the client secret expired, create a new one and update client_secret")
+        } else if message.starts_with("AADSTS700016") {
+            Some("the application was not found in the tenant, check client_id, tenant_id and cloud")
+        } else if message.starts_with("AADSTS90002") {
+            Some("the tenant was not found, check tenant_id and cloud")
+        } else if message.starts_with("AADSTS700027") {
+            Some("the certificate is not registered on the application or it expired, upload the certificate to the application")
+        } else if message.starts_with("AADSTS70021") || message.starts_with("AADSTS700213") {
+            Some("no federated identity credential of the application matches the federated token, check its issuer and subject")
+        } else if code == "Authorization_RequestDenied" || graph_error.status == StatusCode::FORBIDDEN {
+            Some("grant the application the Application.Read.All Microsoft Graph application permission with admin consent")
+        } else if code == "InvalidAuthenticationToken" {
+            Some("the API token was rejected, check that graph_url matches the cloud of the authority host")
+        } else {
+            None
+        }
+    }
+
+    /// Count the error in the metrics
+    pub fn count(&self, tenant_id: &str, endpoint: &'static str) {
+        let labels = [
+            ("tenant_id", tenant_id.to_string()),
+            ("kind", self.kind().to_string()),
+            ("endpoint", endpoint.to_string()),
+        ];
+        metrics::counter!(AZURE_ERRORS_TOTAL, &labels).increment(1);
+    }
+
+    /// Turn error statuses into errors with the details from the response body
+    pub async fn check_response(response: Response) -> Result<Response, AzureError> {
+        let status = response.status();
+        if status.is_success() {
+            return Ok(response);
+        }
+
+        let body = response.text().await.unwrap_or_default();
+        let json: serde_json::Value = serde_json::from_str(&body).unwrap_or_default();
+
+        let (code, message) = match &json["error"] {
+            serde_json::Value::Object(error) => (
+                error.get("code").and_then(|code| code.as_str()),
+                error.get("message").and_then(|message| message.as_str()),
+            ),
+            serde_json::Value::String(code) => (Some(code.as_str()), json["error_description"].as_str()),
+            _ => (None, Some(body.as_str()).filter(|body| !body.is_empty())),
+        };
+
+        let graph_error = GraphError {
+            status,
+            code: code.map(String::from),
+            message: message.map(String::from),
+        };
+
+        Err(match status {
+            StatusCode::UNAUTHORIZED => AzureError::Auth(graph_error),
+            StatusCode::BAD_REQUEST if matches!(code, Some("invalid_client" | "unauthorized_client")) => AzureError::Auth(graph_error),
+            StatusCode::FORBIDDEN => AzureError::Forbidden(graph_error),
+            StatusCode::TOO_MANY_REQUESTS | StatusCode::SERVICE_UNAVAILABLE => AzureError::Throttled(graph_error),
+            _ => AzureError::Http(graph_error),
+        })
+    }
+}
+
+/// Send a request to Azure with retries and deserialize its JSON response, counting any error by kind and endpoint
+pub async fn get_azure_json<T: DeserializeOwned>(
+    request: RequestBuilder,
+    retry: &Retry,
+    tenant_id: &str,
+    endpoint: &'static str,
+) -> Result<T, AzureError> {
+    let result = async {
+        let response = send_with_retries(request, retry, tenant_id, endpoint).await?;
+        Ok::<_, AzureError>(AzureError::check_response(response).await?.json::<T>().await?)
+    };
+
+    result.await.inspect_err(|e| e.count(tenant_id, endpoint))
+}
+
+/// Log and count Azure objects that were skipped because they failed to decode
+pub fn record_decode_failures(tenant_id: &str, object: &'static str, failures: &[DecodeFailure]) {
+    for failure in failures {
+        tracing::warn!(
+            tenant_id,
+            object,
+            id = failure.id,
+            error = failure.error,
+            "skipping azure object that failed to decode"
+        );
+    }
+
+    let labels = [("tenant_id", tenant_id.to_string()), ("object", object.to_string())];
+    metrics::counter!(AZURE_DECODE_ERRORS_TOTAL, &labels).increment(failures.len() as u64);
+}
+
+/// Log and count credentials that failed to decode and are kept without their dates
+pub fn record_credential_decode_errors<T: LenientCredential>(tenant_id: &str, object: &'static str, owner_id: &str, credentials: &[T]) {
+    for credential in credentials {
+        let Some(error) = credential.decode_error() else {
+            continue;
+        };
+
+        tracing::warn!(
+            tenant_id,
+            object,
+            id = owner_id,
+            key_id = credential.key_id(),
+            error,
+            "keeping azure credential that failed to decode without its dates"
+        );
+
+        let labels = [("tenant_id", tenant_id.to_string()), ("object", object.to_string())];
+        metrics::counter!(AZURE_DECODE_ERRORS_TOTAL, &labels).increment(1);
+    }
+}
+
+impl Display for AzureError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AzureError::Auth(e) => write!(f, "authentication failed: {e}"),
+            AzureError::Forbidden(e) => write!(f, "missing permission: {e}"),
+            AzureError::Throttled(e) => write!(f, "throttled: {e}"),
+            AzureError::Http(e) => write!(f, "request failed: {e}"),
+            AzureError::Timeout(e) => write!(f, "request timed out: {e}"),
+            AzureError::Network(e) => write!(f, "network error: {e}"),
+            AzureError::MalformedPayload(e) => write!(f, "malformed response: {e}"),
+            AzureError::Credentials(e) => write!(f, "failed reading credentials: {e}"),
+        }
+    }
+}
+
+impl Display for GraphError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.status)?;
+        if let Some(code) = &self.code {
+            write!(f, " {code}")?;
+        }
+        if let Some(message) = &self.message {
+            write!(f, ": {message}")?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for AzureError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            AzureError::Timeout(e) | AzureError::Network(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<reqwest::Error> for AzureError {
+    fn from(e: reqwest::Error) -> Self {
+        if e.is_timeout() {
+            AzureError::Timeout(e)
+        } else if e.is_decode() {
+            AzureError::MalformedPayload(e.to_string())
+        } else {
+            AzureError::Network(e)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn response(status: u16, body: &str) -> Response {
+        Response::from(axum::http::Response::builder().status(status).body(body.to_string()).unwrap())
+    }
+
+    fn graph_error(status: StatusCode, code: Option<&str>, message: Option<&str>) -> GraphError {
+        GraphError {
+            status,
+            code: code.map(String::from),
+            message: message.map(String::from),
+        }
+    }
+
+    #[tokio::test]
+    async fn check_response_classifies_error_statuses() {
+        let graph_body = r#"{"error": {"code": "Request_ResourceNotFound", "message": "not found"}}"#;
+        let token_body = r#"{"error": "invalid_client", "error_description": "AADSTS7000215: Invalid client secret provided."}"#;
+
+        let cases = [
+            (401, graph_body, "auth", Some("Request_ResourceNotFound"), Some("not found")),
+            (
+                400,
+                token_body,
+                "auth",
+                Some("invalid_client"),
+                Some("AADSTS7000215: Invalid client secret provided."),
+            ),
+            (400, r#"{"error": "invalid_request"}"#, "http", Some("invalid_request"), None),
+            (403, graph_body, "forbidden", Some("Request_ResourceNotFound"), Some("not found")),
+            (429, "", "throttled", None, None),
+            (503, "Service Unavailable", "throttled", None, Some("Service Unavailable")),
+            (404, graph_body, "http", Some("Request_ResourceNotFound"), Some("not found")),
+            (500, "<html>oops</html>", "http", None, Some("<html>oops</html>")),
+        ];
+
+        for (status, body, kind, code, message) in cases {
+            let error = AzureError::check_response(response(status, body)).await.unwrap_err();
+
+            assert_eq!(error.kind(), kind, "{status} {body}");
+            assert_eq!(error.status().map(|status| status.as_u16()), Some(status), "{status} {body}");
+            let (AzureError::Auth(graph_error)
+            | AzureError::Forbidden(graph_error)
+            | AzureError::Throttled(graph_error)
+            | AzureError::Http(graph_error)) = &error
+            else {
+                panic!("{status} {body}: not a graph error");
+            };
+            assert_eq!(graph_error.code.as_deref(), code, "{status} {body}");
+            assert_eq!(graph_error.message.as_deref(), message, "{status} {body}");
+        }
+
+        assert!(AzureError::check_response(response(200, "{}")).await.is_ok());
+    }
+
+    #[test]
+    fn hints_for_known_errors() {
+        let cases = [
+            (
+                AzureError::Auth(graph_error(
+                    StatusCode::UNAUTHORIZED,
+                    Some("invalid_client"),
+                    Some("AADSTS7000215: Invalid client secret"),
+                )),
+                Some("the client secret is invalid"),
+            ),
+            (
+                AzureError::Auth(graph_error(
+                    StatusCode::UNAUTHORIZED,
+                    Some("invalid_client"),
+                    Some("AADSTS7000222: The provided client secret keys are expired"),
+                )),
+                Some("the client secret expired"),
+            ),
+            (
+                AzureError::Http(graph_error(
+                    StatusCode::BAD_REQUEST,
+                    Some("unauthorized_client"),
+                    Some("AADSTS700016: Application not found"),
+                )),
+                Some("the application was not found"),
+            ),
+            (
+                AzureError::Http(graph_error(
+                    StatusCode::BAD_REQUEST,
+                    Some("invalid_request"),
+                    Some("AADSTS90002: Tenant not found"),
+                )),
+                Some("the tenant was not found"),
+            ),
+            (
+                AzureError::Auth(graph_error(
+                    StatusCode::UNAUTHORIZED,
+                    None,
+                    Some("AADSTS700027: Client assertion failed signature validation"),
+                )),
+                Some("the certificate is not registered"),
+            ),
+            (
+                AzureError::Http(graph_error(
+                    StatusCode::BAD_REQUEST,
+                    None,
+                    Some("AADSTS700213: No matching federated identity record found"),
+                )),
+                Some("no federated identity credential"),
+            ),
+            (
+                AzureError::Forbidden(graph_error(StatusCode::FORBIDDEN, Some("Authorization_RequestDenied"), None)),
+                Some("grant the application the Application.Read.All"),
+            ),
+            (
+                AzureError::Auth(graph_error(StatusCode::UNAUTHORIZED, Some("InvalidAuthenticationToken"), None)),
+                Some("the API token was rejected"),
+            ),
+            (
+                AzureError::Throttled(graph_error(StatusCode::TOO_MANY_REQUESTS, None, None)),
+                Some("reduce how often"),
+            ),
+            (
+                AzureError::Http(graph_error(StatusCode::NOT_FOUND, Some("Request_ResourceNotFound"), None)),
+                None,
+            ),
+            (AzureError::MalformedPayload("missing field".into()), None),
+            (AzureError::Credentials("no such file".into()), None),
+        ];
+
+        for (error, hint) in cases {
+            match (error.hint(), hint) {
+                (Some(actual), Some(expected)) => assert!(actual.starts_with(expected), "{error}: {actual}"),
+                (actual, expected) => assert_eq!(actual, expected, "{error}"),
+            }
+        }
+    }
+}
@@ -20,7 +20,17 @@
 pub mod api_token_updater;
 pub mod application_metrics_updater;
 pub mod applications_updater;
+pub mod azure_error;
+pub mod service_principals_updater;
+pub mod settings_reloader;
+pub mod supervisor;
+pub mod systemd_notifier;
 
 pub use api_token_updater::*;
 pub use application_metrics_updater::*;
 pub use applications_updater::*;
+pub use azure_error::*;
+pub use service_principals_updater::*;
+pub use settings_reloader::*;
+pub use supervisor::*;
+pub use systemd_notifier::*;
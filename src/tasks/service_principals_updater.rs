@@ -0,0 +1,139 @@
+/*
+ * Licensed to the Apache Software Foundation (ASF) under one
+ * or more contributor license agreements.  See the NOTICE file
+ * distributed with this work for additional information
+ * regarding copyright ownership.  The ASF licenses this file
+ * to you under the Apache License, Version 2.0 (the
+ * "License"); you may not use this file except in compliance
+ * with the License.  You may obtain a copy of the License at
+ *
+ *   http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing,
+ * software distributed under the License is distributed on an
+ * "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+ * KIND, either express or implied.  See the License for the
+ * specific language governing permissions and limitations
+ * under the License.
+ */
+
+use std::time::{Duration, Instant};
+
+use crate::{
+    app_metrics::SERVICE_PRINCIPALS_SECONDS,
+    global_state::{GlobalState, TenantState},
+    tasks::{get_azure_json, record_credential_decode_errors, record_decode_failures, AzureError},
+    types::{lenient, service_principals::AzureServicePrincipals},
+};
+
+/// https://learn.microsoft.com/en-us/graph/query-parameters
+/// https://learn.microsoft.com/en-us/graph/api/serviceprincipal-list?view=graph-rest-1.0
+pub async fn azure_service_principals_updater(global_state: &GlobalState, tenant: &TenantState) {
+    // This fn is spawned in a thread simultaneously with another thread
+    // responsible for updating the api token, so we should wait for it to finish
+    while tenant.azure_api_token.read().is_empty() {
+        tracing::warn!(tenant_id = tenant.tenant_id, "azure api token not yet acquired, sleeping 5 seconds");
+        tokio::time::sleep(Duration::from_secs(5)).await;
+    }
+
+    let get_service_principals = |url| async move {
+        tracing::debug!(tenant_id = tenant.tenant_id, url, "getting azure service principals with api token");
+
+        let request = global_state.http_client.get(url).bearer_auth(tenant.azure_api_token.read());
+
+        get_azure_json::<AzureServicePrincipals>(request, &global_state.settings().retry, &tenant.tenant_id, "service_principals").await
+    };
+
+    let inner = || async move {
+        let service_principals_settings = global_state.tenant_settings(tenant).service_principals;
+        let mut response = get_service_principals(format!(
+            "{}?$top={}&$select=id,appId,displayName,servicePrincipalType,preferredTokenSigningKeyThumbprint,passwordCredentials,keyCredentials",
+            service_principals_settings.url, service_principals_settings.results_per_page
+        ))
+        .await?;
+
+        while let Some(next_link) = response.next_link {
+            let mut next_response = get_service_principals(next_link).await?;
+
+            response.next_link = next_response.next_link;
+            response.value.append(&mut next_response.value);
+        }
+
+        let (parsed_service_principals, decode_failures) = lenient::partition(response.value);
+
+        record_decode_failures(&tenant.tenant_id, "service_principal", &decode_failures);
+        for service_principal in parsed_service_principals.iter() {
+            let id = &service_principal.id;
+            record_credential_decode_errors(&tenant.tenant_id, "password_credential", id, &service_principal.password_credentials);
+            record_credential_decode_errors(&tenant.tenant_id, "key_credential", id, &service_principal.key_credentials);
+        }
+
+        let mut service_principals = tenant.service_principals.write();
+        service_principals.clear();
+        service_principals.extend(
+            parsed_service_principals
+                .into_iter()
+                .map(|service_principal| (service_principal.id.clone(), service_principal)),
+        );
+        *tenant.service_principal_decode_failures.write() = decode_failures;
+
+        Ok::<_, AzureError>(())
+    };
+
+    loop {
+        // Get the settings on every refresh to pick up reloaded settings
+        let service_principals_settings = global_state.tenant_settings(tenant).service_principals;
+        let start = Instant::now();
+
+        let result = inner().await;
+
+        let elapsed = start.elapsed();
+        let took_millis = elapsed.as_millis() as u64;
+        let next_update_in_millis = service_principals_settings.cache_refresh_interval.as_millis() as u64;
+
+        let service_principals_cached = tenant.service_principals.read().len();
+
+        let status_label = match result {
+            Ok(_) => {
+                tenant.service_principals_status.write().record_success();
+
+                tracing::info!(
+                    tenant_id = tenant.tenant_id,
+                    took_millis,
+                    next_update_in_millis,
+                    service_principals_cached,
+                    "updated azure service principals"
+                );
+
+                "success"
+            }
+            Err(e) => {
+                let mut service_principals_status = tenant.service_principals_status.write();
+                service_principals_status.record_failure();
+
+                // The cache keeps the service principals of the last successful refresh
+                tracing::error!(
+                    tenant_id = tenant.tenant_id,
+                    took_millis,
+                    next_update_in_millis,
+                    service_principals_cached,
+                    consecutive_failures = service_principals_status.consecutive_failures,
+                    error_kind = e.kind(),
+                    hint = e.hint(),
+                    error = %e,
+                    "failed updating azure service principals"
+                );
+
+                "fail"
+            }
+        };
+
+        metrics::histogram!(
+            SERVICE_PRINCIPALS_SECONDS,
+            &[("tenant_id", tenant.tenant_id.clone()), ("status", status_label.to_string())]
+        )
+        .record(elapsed);
+
+        tokio::time::sleep(service_principals_settings.cache_refresh_interval).await
+    }
+}
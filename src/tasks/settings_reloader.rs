@@ -0,0 +1,304 @@
+/*
+ * Licensed to the Apache Software Foundation (ASF) under one
+ * or more contributor license agreements.  See the NOTICE file
+ * distributed with this work for additional information
+ * regarding copyright ownership.  The ASF licenses this file
+ * to you under the Apache License, Version 2.0 (the
+ * "License"); you may not use this file except in compliance
+ * with the License.  You may obtain a copy of the License at
+ *
+ *   http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing,
+ * software distributed under the License is distributed on an
+ * "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+ * KIND, either express or implied.  See the License for the
+ * specific language governing permissions and limitations
+ * under the License.
+ */
+
+use std::{path::Path, sync::Arc, time::SystemTime};
+
+use chrono::Utc;
+use tokio::signal::unix::{signal, SignalKind};
+
+use crate::{
+    app_metrics::{SETTINGS_LAST_RELOAD_SUCCESS, SETTINGS_LAST_RELOAD_SUCCESSFUL, SETTINGS_RELOADS_TOTAL, TLS_CERTIFICATE_EXPIRY},
+    global_state::{GlobalState, ListenerState},
+    settings::app_settings::{self, Listener, Settings},
+    tasks,
+    utils::{self, ServingCertificate},
+};
+
+/// Reload the settings on SIGHUP and, if enabled, when the settings file changes.
+/// The TLS certificates are reloaded along with the settings, and on their own when the cert, key or client CA file of a listener changes,
+/// e.g. when it is renewed. Reading the files and building the TLS configs blocks, so the reloads run off the async worker threads
+pub async fn settings_reloader(global_state: &'static GlobalState) {
+    let mut sighup = signal(SignalKind::hangup()).expect("failed installing the SIGHUP handler");
+    let mut settings_modified = modified(&global_state.settings_path);
+    let mut tls_modified = listeners_tls_files_modified(global_state);
+
+    loop {
+        set_metrics(global_state);
+
+        let settings = global_state.settings();
+        let (watch, watch_interval) = (settings.reload.watch, settings.reload.watch_interval);
+        drop(settings);
+
+        tokio::select! {
+            _ = sighup.recv() => {
+                tracing::info!("received SIGHUP, reloading settings");
+                settings_modified = modified(&global_state.settings_path);
+                let _ = tokio::task::spawn_blocking(move || reload(global_state, "sighup")).await;
+            }
+            // Also wake up if the files are not watched, to keep setting the metrics so they are not pruned
+            _ = tokio::time::sleep(watch_interval) => {
+                if !watch {
+                    continue;
+                }
+
+                let modified_now = modified(&global_state.settings_path);
+
+                // The files are not reloaded while they are missing, e.g. while they are being replaced
+                if modified_now.is_some() && modified_now != settings_modified {
+                    tracing::info!(path = global_state.settings_path, "settings file changed, reloading settings");
+                    let _ = tokio::task::spawn_blocking(move || reload(global_state, "file_change")).await;
+                }
+                settings_modified = modified_now;
+
+                let modified_now = listeners_tls_files_modified(global_state);
+
+                for ((listener_state, modified_now), modified_before) in global_state.listeners.iter().zip(&modified_now).zip(&tls_modified) {
+                    if listener_state.tls_config.is_some() && modified_now[0].is_some() && modified_now != modified_before {
+                        tracing::info!(
+                            listener = %listener_state.listen_address,
+                            "tls cert, key or client ca file changed, reloading tls certificate"
+                        );
+                        let _ = tokio::task::spawn_blocking(move || reload_tls_certificate(global_state, listener_state)).await;
+                    }
+                }
+                tls_modified = modified_now;
+            }
+        }
+    }
+}
+
+fn modified(path: impl AsRef<Path>) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|metadata| metadata.modified()).ok()
+}
+
+/// Modification times of the cert file, key file and client CA file of each listener, in the order of the listener states
+fn listeners_tls_files_modified(global_state: &GlobalState) -> Vec<[Option<SystemTime>; 3]> {
+    let settings = global_state.settings();
+
+    global_state
+        .listeners
+        .iter()
+        .map(|listener_state| match listener_settings(&settings, listener_state) {
+            Some(listener) => {
+                [&listener.cert_file, &listener.key_file, &listener.tls(&settings).client_ca_file].map(|path| path.as_ref().and_then(modified))
+            }
+            None => [None; 3],
+        })
+        .collect()
+}
+
+/// The settings of the listener bound to the address of the listener state. Reloads cannot add or remove listeners, but a listener removed from
+/// the settings keeps serving with its current TLS config until the next restart
+fn listener_settings(settings: &Settings, listener_state: &ListenerState) -> Option<Listener> {
+    settings
+        .web
+        .listeners()
+        .into_iter()
+        .find(|listener| listener.listen_address == listener_state.listen_address)
+}
+
+/// Rebuild the TLS config of a listener with the current settings to pick up a renewed cert and key. On errors, the current certificate is kept
+fn reload_tls_certificate(global_state: &GlobalState, listener_state: &ListenerState) {
+    // Do not race a settings reload, which also rebuilds the TLS configs
+    let _reload_status = global_state.settings_reload_status.write();
+
+    let settings = global_state.settings();
+    let Some(listener) = listener_settings(&settings, listener_state) else {
+        return;
+    };
+
+    match utils::tls_config_from_settings(&listener, &settings) {
+        Some(Ok((server_config, serving_certificate))) => apply_tls_config(listener_state, server_config, serving_certificate),
+        Some(Err(e)) => tracing::error!(
+            listener = %listener_state.listen_address,
+            error = e,
+            "failed reloading tls certificate, keeping the current certificate"
+        ),
+        None => {}
+    }
+}
+
+fn apply_tls_config(listener_state: &ListenerState, server_config: rustls::ServerConfig, serving_certificate: ServingCertificate) {
+    let Some(tls_config) = &listener_state.tls_config else {
+        return;
+    };
+
+    tls_config.reload_from_config(Arc::new(server_config));
+
+    tracing::info!(
+        listener = %listener_state.listen_address,
+        subject = serving_certificate.subject,
+        not_after = serving_certificate.not_after.to_rfc3339(),
+        "reloaded tls certificate"
+    );
+    *listener_state.serving_certificate.write() = Some(serving_certificate);
+}
+
+/// Re-read the settings file and apply it. Invalid settings are rejected and the current settings are kept
+pub fn reload(global_state: &GlobalState, trigger: &'static str) -> Result<(), String> {
+    // Holding the lock during the reload makes sure simultaneous reloads do not overwrite each other's settings
+    let mut reload_status = global_state.settings_reload_status.write();
+
+    let result = apply_settings_file(global_state);
+
+    match &result {
+        Ok(()) => {
+            reload_status.last_success = Utc::now();
+            reload_status.successful = true;
+            tracing::info!(trigger, "reloaded settings");
+        }
+        Err(e) => {
+            reload_status.successful = false;
+            tracing::error!(trigger, error = e, "failed reloading settings, keeping the current settings");
+        }
+    }
+    drop(reload_status);
+
+    let labels = [("trigger", trigger), ("result", if result.is_ok() { "success" } else { "fail" })];
+    metrics::counter!(SETTINGS_RELOADS_TOTAL, &labels).increment(1);
+    set_metrics(global_state);
+
+    result
+}
+
+/// Validate everything before changing anything, so either all or none of the new settings are applied
+fn apply_settings_file(global_state: &GlobalState) -> Result<(), String> {
+    let new_settings = app_settings::parse(&global_state.settings_path)?;
+    let old_settings = global_state.settings();
+
+    // There is a tenant state and background tasks for each tenant, which are only created at startup
+    let tenant_ids = |settings: &Settings| {
+        let mut tenant_ids = settings
+            .tenants
+            .iter()
+            .map(|tenant| tenant.credentials.tenant_id.clone())
+            .collect::<Vec<_>>();
+        tenant_ids.sort();
+        tenant_ids
+    };
+    if tenant_ids(&old_settings) != tenant_ids(&new_settings) {
+        return Err("adding or removing tenants requires a restart".into());
+    }
+
+    let mut tls_configs = Vec::new();
+    for listener_state in global_state.listeners.iter().filter(|listener_state| listener_state.tls_config.is_some()) {
+        let tls_config = listener_settings(&new_settings, listener_state)
+            .and_then(|listener| utils::tls_config_from_settings(&listener, &new_settings))
+            .transpose()?;
+
+        if let Some((server_config, serving_certificate)) = tls_config {
+            tls_configs.push((listener_state, server_config, serving_certificate));
+        }
+    }
+
+    let restart_required = restart_required_changes(&old_settings, &new_settings);
+    if !restart_required.is_empty() {
+        tracing::warn!(
+            settings = restart_required.join(", "),
+            "some changed settings only take effect after a restart"
+        );
+    }
+
+    let credentials_changed = global_state
+        .tenants
+        .iter()
+        .filter(|tenant| {
+            old_settings.tenant(&tenant.tenant_id).map(|t| &t.credentials) != new_settings.tenant(&tenant.tenant_id).map(|t| &t.credentials)
+        })
+        .collect::<Vec<_>>();
+
+    global_state.set_settings(new_settings);
+
+    for (listener_state, server_config, serving_certificate) in tls_configs {
+        apply_tls_config(listener_state, server_config, serving_certificate);
+    }
+
+    for tenant in credentials_changed {
+        tracing::info!(tenant_id = tenant.tenant_id, "credentials changed, updating azure api token");
+        tenant.credentials_changed.notify_one();
+    }
+
+    // Changed policies apply to the cached applications right away instead of after the next refresh
+    for tenant in global_state.tenants.iter() {
+        tasks::update_policy_violations(global_state, tenant);
+    }
+
+    Ok(())
+}
+
+/// Settings which are only used at startup, e.g. to bind the server or to spawn the background tasks
+fn restart_required_changes(old_settings: &Settings, new_settings: &Settings) -> Vec<&'static str> {
+    let mut changes = Vec::new();
+
+    let listeners = |settings: &Settings| {
+        settings
+            .web
+            .listeners()
+            .into_iter()
+            .map(|listener| (listener.listen_address, listener.endpoints, listener.cert_file.is_some()))
+            .collect::<Vec<_>>()
+    };
+
+    if listeners(old_settings) != listeners(new_settings) {
+        changes.push("web.listeners");
+    }
+    if old_settings.metrics.prune_interval != new_settings.metrics.prune_interval {
+        changes.push("metrics.prune_interval");
+    }
+    if old_settings.metrics.compute_on_scrape != new_settings.metrics.compute_on_scrape {
+        changes.push("metrics.compute_on_scrape");
+    }
+    if old_settings.openapi != new_settings.openapi {
+        changes.push("openapi");
+    }
+    if old_settings.debug.no_verify_tls != new_settings.debug.no_verify_tls {
+        changes.push("debug.no_verify_tls");
+    }
+
+    let enabled_changed = |enabled: fn(&app_settings::Tenant) -> bool| {
+        old_settings
+            .tenants
+            .iter()
+            .any(|old_tenant| new_settings.tenant(&old_tenant.credentials.tenant_id).map(enabled) != Some(enabled(old_tenant)))
+    };
+    if enabled_changed(|tenant| tenant.applications.enabled) {
+        changes.push("applications.enabled");
+    }
+    if enabled_changed(|tenant| tenant.service_principals.enabled) {
+        changes.push("service_principals.enabled");
+    }
+
+    changes
+}
+
+// Also set on every iteration of the reloader instead of only after reloads, so they are not pruned
+fn set_metrics(global_state: &GlobalState) {
+    let reload_status = global_state.settings_reload_status.read();
+
+    metrics::gauge!(SETTINGS_LAST_RELOAD_SUCCESSFUL).set(if reload_status.successful { 1.0 } else { 0.0 });
+    metrics::gauge!(SETTINGS_LAST_RELOAD_SUCCESS).set(reload_status.last_success.timestamp() as f64);
+
+    for listener_state in global_state.listeners.iter() {
+        if let Some(serving_certificate) = &*listener_state.serving_certificate.read() {
+            // Without a subject label, so a renewed certificate with another subject does not leave the old one's expiry behind
+            metrics::gauge!(TLS_CERTIFICATE_EXPIRY, "listener" => listener_state.listen_address.to_string())
+                .set(serving_certificate.not_after.timestamp() as f64);
+        }
+    }
+}
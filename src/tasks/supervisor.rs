@@ -0,0 +1,111 @@
+/*
+ * Licensed to the Apache Software Foundation (ASF) under one
+ * or more contributor license agreements.  See the NOTICE file
+ * distributed with this work for additional information
+ * regarding copyright ownership.  The ASF licenses this file
+ * to you under the Apache License, Version 2.0 (the
+ * "License"); you may not use this file except in compliance
+ * with the License.  You may obtain a copy of the License at
+ *
+ *   http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing,
+ * software distributed under the License is distributed on an
+ * "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+ * KIND, either express or implied.  See the License for the
+ * specific language governing permissions and limitations
+ * under the License.
+ */
+
+//! Supervise the background tasks, so that a task that panics does not silently stop updating the caches or metrics forever.
+
+use std::{
+    future::Future,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    app_metrics::{BACKGROUND_TASK_RESTARTS_TOTAL, BACKGROUND_TASK_UP},
+    global_state::{BackgroundTask, GlobalState, TaskState},
+};
+
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(60 * 5);
+
+/// Spawn a background task which is restarted with exponential backoff whenever it panics or returns
+pub fn spawn_supervised<F, Fut>(global_state: &'static GlobalState, name: String, task: F)
+where
+    F: Fn() -> Fut + Send + 'static,
+    Fut: Future<Output = ()> + Send + 'static,
+{
+    let mut background_tasks = global_state.background_tasks.write();
+    let index = background_tasks.len();
+    background_tasks.push(BackgroundTask {
+        name,
+        state: TaskState::Running,
+        restarts: 0,
+    });
+
+    tokio::spawn(supervise(global_state, index, task));
+}
+
+async fn supervise<F, Fut>(global_state: &GlobalState, index: usize, task: F)
+where
+    F: Fn() -> Fut,
+    Fut: Future<Output = ()> + Send + 'static,
+{
+    let name = global_state.background_tasks.read()[index].name.clone();
+    let mut backoff = INITIAL_BACKOFF;
+
+    loop {
+        set_state(global_state, index, TaskState::Running);
+
+        let started = Instant::now();
+        let mut handle = tokio::spawn(task());
+
+        let result = loop {
+            tokio::select! {
+                result = &mut handle => break result,
+                // Keep setting the state metric while the task runs, so it is not pruned
+                _ = tokio::time::sleep(Duration::from_secs(60)) => set_state(global_state, index, TaskState::Running),
+            }
+        };
+
+        // Only back off further if the task keeps crashing soon after being restarted
+        if started.elapsed() > MAX_BACKOFF {
+            backoff = INITIAL_BACKOFF;
+        }
+        let restart_in_millis = backoff.as_millis() as u64;
+
+        match result {
+            Ok(()) => tracing::error!(task = name, restart_in_millis, "background task returned unexpectedly"),
+            Err(e) if e.is_panic() => {
+                let panic = e.into_panic();
+                let message = panic
+                    .downcast_ref::<&str>()
+                    .copied()
+                    .or_else(|| panic.downcast_ref::<String>().map(String::as_str))
+                    .unwrap_or("unknown panic payload");
+
+                tracing::error!(task = name, restart_in_millis, panic = message, "background task panicked");
+            }
+            Err(e) => tracing::error!(task = name, restart_in_millis, error = %e, "background task was cancelled"),
+        }
+
+        set_state(global_state, index, TaskState::Restarting);
+        global_state.background_tasks.write()[index].restarts += 1;
+        metrics::counter!(BACKGROUND_TASK_RESTARTS_TOTAL, &[("task", name.clone())]).increment(1);
+
+        tokio::time::sleep(backoff).await;
+        backoff = (backoff * 2).min(MAX_BACKOFF);
+    }
+}
+
+fn set_state(global_state: &GlobalState, index: usize, state: TaskState) {
+    let mut background_tasks = global_state.background_tasks.write();
+    let background_task = &mut background_tasks[index];
+    background_task.state = state;
+
+    let up = if state == TaskState::Running { 1.0 } else { 0.0 };
+    metrics::gauge!(BACKGROUND_TASK_UP, &[("task", background_task.name.clone())]).set(up);
+}
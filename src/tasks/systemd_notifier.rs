@@ -0,0 +1,72 @@
+/*
+ * Licensed to the Apache Software Foundation (ASF) under one
+ * or more contributor license agreements.  See the NOTICE file
+ * distributed with this work for additional information
+ * regarding copyright ownership.  The ASF licenses this file
+ * to you under the Apache License, Version 2.0 (the
+ * "License"); you may not use this file except in compliance
+ * with the License.  You may obtain a copy of the License at
+ *
+ *   http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing,
+ * software distributed under the License is distributed on an
+ * "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+ * KIND, either express or implied.  See the License for the
+ * specific language governing permissions and limitations
+ * under the License.
+ */
+
+use std::time::Duration;
+
+use sd_notify::NotifyState;
+
+use crate::global_state::GlobalState;
+
+/// Notify systemd that the exporter is ready once the caches of all tenants finished their first refresh, even if it failed, so an
+/// Azure outage at startup does not make systemd kill the exporter for not starting up in time. Then ping the systemd watchdog, if enabled with `WatchdogSec=`, as long as no background task is crashed.
+/// Only does something if the exporter runs as a systemd service with `Type=notify`
+pub async fn systemd_notifier(global_state: &GlobalState) {
+    while !global_state.first_attempt_completed() {
+        tokio::time::sleep(Duration::from_secs(1)).await;
+    }
+
+    // The failed caches are retried in the background, and /readyz keeps failing until they are refreshed
+    let status =
+        if global_state.tenants.iter().any(|tenant| {
+            tenant.applications_status.read().consecutive_failures > 0 || tenant.service_principals_status.read().consecutive_failures > 0
+        }) {
+            "first cache refresh failed, retrying"
+        } else {
+            "caches refreshed"
+        };
+
+    match sd_notify::notify(false, &[NotifyState::Ready, NotifyState::Status(status)]) {
+        Ok(()) => tracing::info!("notified systemd that the exporter is ready"),
+        Err(e) => tracing::warn!(error = %e, "failed notifying systemd that the exporter is ready"),
+    }
+
+    let mut watchdog_usec = 0;
+    if !sd_notify::watchdog_enabled(false, &mut watchdog_usec) {
+        // Returning would make the supervisor restart the task
+        return std::future::pending().await;
+    }
+
+    // Ping twice per watchdog timeout as recommended by sd_watchdog_enabled(3)
+    let ping_interval = Duration::from_micros(watchdog_usec) / 2;
+    tracing::info!(ping_interval_millis = ping_interval.as_millis() as u64, "pinging the systemd watchdog");
+
+    loop {
+        tokio::time::sleep(ping_interval).await;
+
+        // Let systemd restart the exporter if a background task keeps crashing, like the liveness probe does
+        if !global_state.background_tasks_running() {
+            tracing::warn!("a background task is crashed, not pinging the systemd watchdog");
+            continue;
+        }
+
+        if let Err(e) = sd_notify::notify(false, &[NotifyState::Watchdog]) {
+            tracing::warn!(error = %e, "failed pinging the systemd watchdog");
+        }
+    }
+}
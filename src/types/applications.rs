@@ -17,16 +17,94 @@
  * under the License.
  */
 
+use std::collections::HashMap;
+
+use base64::{engine::general_purpose::STANDARD, Engine};
 use chrono::{DateTime, NaiveDateTime, Utc};
 use serde::{Deserialize, Deserializer, Serialize};
 use utoipa::ToSchema;
 
+use crate::types::lenient::{de_credentials, de_optional_credentials, Lenient, LenientCredential};
+
 /// https://learn.microsoft.com/en-us/graph/api/resources/application?view=graph-rest-1.0#properties
 #[derive(Debug, Deserialize)]
 pub struct AzureApplications {
     #[serde(rename = "@odata.nextLink")]
     pub next_link: Option<String>,
-    pub value: Vec<AzureApplication>,
+    pub value: Vec<Lenient<AzureApplication>>,
+}
+
+/// https://learn.microsoft.com/en-us/graph/api/application-delta?view=graph-rest-1.0
+#[derive(Debug, Deserialize)]
+pub struct AzureApplicationsDelta {
+    #[serde(rename = "@odata.nextLink")]
+    pub next_link: Option<String>,
+    /// Only present on the last page, used to request the changes made after this response
+    #[serde(rename = "@odata.deltaLink")]
+    pub delta_link: Option<String>,
+    pub value: Vec<Lenient<AzureApplicationDelta>>,
+}
+
+/// A changed application in a delta response. Changed applications are not guaranteed to include the properties that did not change,
+/// so every property except the ID is optional
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AzureApplicationDelta {
+    pub id: String,
+    pub app_id: Option<String>,
+    pub display_name: Option<String>,
+    #[serde(deserialize_with = "parse_date_time", default)]
+    pub created_date_time: Option<DateTime<Utc>>,
+    #[serde(deserialize_with = "de_optional_credentials", default)]
+    pub password_credentials: Option<Vec<PasswordCredential>>,
+    #[serde(deserialize_with = "de_optional_credentials", default)]
+    pub key_credentials: Option<Vec<KeyCredential>>,
+    /// Present if the application was deleted
+    #[serde(rename = "@removed")]
+    pub removed: Option<serde_json::Value>,
+}
+
+impl AzureApplicationDelta {
+    /// Add, update or remove the application in the given HashMap of id -> application
+    pub fn apply(self, applications: &mut HashMap<String, AzureApplication>) {
+        if self.removed.is_some() {
+            applications.remove(&self.id);
+            return;
+        }
+
+        if let Some(application) = applications.get_mut(&self.id) {
+            if let Some(app_id) = self.app_id {
+                application.app_id = app_id;
+            }
+            if self.display_name.is_some() {
+                application.display_name = self.display_name;
+            }
+            if self.created_date_time.is_some() {
+                application.created_date_time = self.created_date_time;
+            }
+            if let Some(password_credentials) = self.password_credentials {
+                application.password_credentials = password_credentials;
+            }
+            if let Some(key_credentials) = self.key_credentials {
+                application.key_credentials = key_credentials;
+            }
+        } else if let Some(app_id) = self.app_id {
+            let application = AzureApplication {
+                id: self.id.clone(),
+                app_id,
+                display_name: self.display_name,
+                created_date_time: self.created_date_time,
+                password_credentials: self.password_credentials.unwrap_or_default(),
+                key_credentials: self.key_credentials.unwrap_or_default(),
+            };
+            applications.insert(self.id, application);
+        } else {
+            tracing::warn!(
+                id = self.id,
+                "ignoring changed azure application that is neither cached nor has an app id"
+            );
+        }
+    }
 }
 
 #[derive(Debug, Deserialize, Serialize, ToSchema)]
@@ -35,8 +113,14 @@ pub struct AzureApplication {
     pub id: String,
     pub app_id: String,
     pub display_name: Option<String>,
+    #[serde(deserialize_with = "parse_date_time", default)]
+    pub created_date_time: Option<DateTime<Utc>>,
+    #[serde(deserialize_with = "de_credentials")]
     #[schema(inline)]
     pub password_credentials: Vec<PasswordCredential>,
+    #[serde(deserialize_with = "de_credentials", default)]
+    #[schema(inline)]
+    pub key_credentials: Vec<KeyCredential>,
 }
 
 #[derive(Debug, Deserialize, Serialize, ToSchema)]
@@ -44,22 +128,139 @@ pub struct AzureApplication {
 pub struct PasswordCredential {
     pub key_id: String,
     pub display_name: Option<String>,
+    #[serde(deserialize_with = "parse_date_time", default)]
+    pub start_date_time: Option<DateTime<Utc>>,
     #[serde(deserialize_with = "parse_date_time")]
     pub end_date_time: Option<DateTime<Utc>>,
+    /// Why the credential could not be decoded, in which case only its key ID and display name are known
+    #[serde(skip_deserializing, skip_serializing_if = "Option::is_none")]
+    pub decode_error: Option<String>,
+}
+
+impl LenientCredential for PasswordCredential {
+    fn invalid(value: &serde_json::Value, error: String) -> Self {
+        Self {
+            key_id: value["keyId"].as_str().unwrap_or_default().into(),
+            display_name: value["displayName"].as_str().map(String::from),
+            start_date_time: None,
+            end_date_time: None,
+            decode_error: Some(error),
+        }
+    }
+
+    fn key_id(&self) -> &str {
+        &self.key_id
+    }
+
+    fn decode_error(&self) -> Option<&str> {
+        self.decode_error.as_deref()
+    }
+
+    fn end_date_time(&self) -> Option<DateTime<Utc>> {
+        self.end_date_time
+    }
 }
 
 impl PasswordCredential {
     /// Return the remaining seconds until the password credential expires
     /// If an end time is not set, return positive infinity
     pub fn remaining_seconds(&self) -> f64 {
-        let Some(ref end_date_time) = self.end_date_time else {
-            return f64::INFINITY;
-        };
+        remaining_seconds(&self.end_date_time)
+    }
+
+    /// Whether the password credential has started and not expired yet at the given time.
+    /// Missing dates do not limit when the credential is valid
+    pub fn is_active(&self, now: DateTime<Utc>) -> bool {
+        is_active(self.start_date_time, self.end_date_time, now)
+    }
+}
+
+/// https://learn.microsoft.com/en-us/graph/api/resources/keycredential?view=graph-rest-1.0#properties
+#[derive(Debug, Deserialize, Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct KeyCredential {
+    pub key_id: String,
+    pub display_name: Option<String>,
+    /// Usually "AsymmetricX509Cert" or "Symmetric"
+    #[serde(rename = "type")]
+    pub key_type: Option<String>,
+    /// Usually "Verify" or "Sign"
+    pub usage: Option<String>,
+    /// Base64 encoded identifier, which for certificates is usually the SHA-1 thumbprint
+    pub custom_key_identifier: Option<String>,
+    #[serde(deserialize_with = "parse_date_time", default)]
+    pub start_date_time: Option<DateTime<Utc>>,
+    #[serde(deserialize_with = "parse_date_time")]
+    pub end_date_time: Option<DateTime<Utc>>,
+    /// Why the credential could not be decoded, in which case only its key ID and display name are known
+    #[serde(skip_deserializing, skip_serializing_if = "Option::is_none")]
+    pub decode_error: Option<String>,
+}
+
+impl LenientCredential for KeyCredential {
+    fn invalid(value: &serde_json::Value, error: String) -> Self {
+        Self {
+            key_id: value["keyId"].as_str().unwrap_or_default().into(),
+            display_name: value["displayName"].as_str().map(String::from),
+            key_type: None,
+            usage: None,
+            custom_key_identifier: None,
+            start_date_time: None,
+            end_date_time: None,
+            decode_error: Some(error),
+        }
+    }
 
-        (*end_date_time - Utc::now()).num_seconds() as f64
+    fn key_id(&self) -> &str {
+        &self.key_id
+    }
+
+    fn decode_error(&self) -> Option<&str> {
+        self.decode_error.as_deref()
+    }
+
+    fn end_date_time(&self) -> Option<DateTime<Utc>> {
+        self.end_date_time
     }
 }
 
+impl KeyCredential {
+    /// Return the remaining seconds until the key credential expires
+    /// If an end time is not set, return positive infinity
+    pub fn remaining_seconds(&self) -> f64 {
+        remaining_seconds(&self.end_date_time)
+    }
+
+    /// Whether the key credential has started and not expired yet at the given time.
+    /// Missing dates do not limit when the credential is valid
+    pub fn is_active(&self, now: DateTime<Utc>) -> bool {
+        is_active(self.start_date_time, self.end_date_time, now)
+    }
+
+    /// Return the custom key identifier decoded as an uppercase hex string, which is how Azure shows certificate thumbprints.
+    /// If the identifier is not valid base64, return it as-is
+    pub fn thumbprint(&self) -> Option<String> {
+        let custom_key_identifier = self.custom_key_identifier.as_ref()?;
+
+        match STANDARD.decode(custom_key_identifier) {
+            Ok(bytes) => Some(bytes.iter().map(|b| format!("{b:02X}")).collect()),
+            Err(_) => Some(custom_key_identifier.clone()),
+        }
+    }
+}
+
+fn remaining_seconds(end_date_time: &Option<DateTime<Utc>>) -> f64 {
+    let Some(ref end_date_time) = end_date_time else {
+        return f64::INFINITY;
+    };
+
+    (*end_date_time - Utc::now()).num_seconds() as f64
+}
+
+fn is_active(start_date_time: Option<DateTime<Utc>>, end_date_time: Option<DateTime<Utc>>, now: DateTime<Utc>) -> bool {
+    start_date_time.is_none_or(|start_date_time| start_date_time <= now) && end_date_time.is_none_or(|end_date_time| end_date_time > now)
+}
+
 fn parse_date_time<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<DateTime<Utc>>, D::Error> {
     let maybe_string_time = Option::<String>::deserialize(deserializer)?;
 
@@ -72,3 +273,88 @@ fn parse_date_time<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<
         })
         .transpose()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cached_applications() -> HashMap<String, AzureApplication> {
+        let application: AzureApplication = serde_json::from_value(serde_json::json!({
+            "id": "id1",
+            "appId": "app1",
+            "displayName": "App 1",
+            "createdDateTime": "2020-01-01T00:00:00Z",
+            "passwordCredentials": [{"keyId": "k1", "endDateTime": "2030-01-01T00:00:00Z"}],
+            "keyCredentials": [{"keyId": "c1", "endDateTime": "2030-01-01T00:00:00Z"}],
+        }))
+        .unwrap();
+
+        HashMap::from([(application.id.clone(), application)])
+    }
+
+    fn apply(applications: &mut HashMap<String, AzureApplication>, change: serde_json::Value) {
+        serde_json::from_value::<AzureApplicationDelta>(change).unwrap().apply(applications);
+    }
+
+    #[test]
+    fn delta_merges_changed_properties_into_cached_application() {
+        let mut applications = cached_applications();
+
+        apply(
+            &mut applications,
+            serde_json::json!({"id": "id1", "displayName": "Renamed", "keyCredentials": []}),
+        );
+
+        let application = &applications["id1"];
+        assert_eq!(application.app_id, "app1");
+        assert_eq!(application.display_name.as_deref(), Some("Renamed"));
+        assert!(application.created_date_time.is_some());
+        assert_eq!(application.password_credentials.len(), 1);
+        assert!(application.key_credentials.is_empty());
+    }
+
+    #[test]
+    fn delta_removes_deleted_application() {
+        let mut applications = cached_applications();
+
+        apply(&mut applications, serde_json::json!({"id": "id1", "@removed": {"reason": "deleted"}}));
+
+        assert!(applications.is_empty());
+    }
+
+    #[test]
+    fn delta_inserts_new_application_only_with_app_id() {
+        let mut applications = cached_applications();
+
+        apply(&mut applications, serde_json::json!({"id": "id2", "displayName": "No app id"}));
+        assert!(!applications.contains_key("id2"));
+
+        apply(
+            &mut applications,
+            serde_json::json!({"id": "id2", "appId": "app2", "passwordCredentials": [{"keyId": "k2", "endDateTime": null}]}),
+        );
+        assert_eq!(applications["id2"].app_id, "app2");
+        assert_eq!(applications["id2"].password_credentials.len(), 1);
+        assert!(applications["id2"].key_credentials.is_empty());
+        assert_eq!(applications.len(), 2);
+    }
+
+    #[test]
+    fn thumbprint_is_the_custom_key_identifier_as_hex() {
+        let certificate = |custom_key_identifier: Option<&str>| -> KeyCredential {
+            serde_json::from_value(serde_json::json!({
+                "keyId": "c1",
+                "customKeyIdentifier": custom_key_identifier,
+                "endDateTime": "2030-01-01T00:00:00Z",
+            }))
+            .unwrap()
+        };
+
+        assert_eq!(
+            certificate(Some("Xjsfmgx9ROKxqPBsk9Jed0Gwyp8=")).thumbprint().as_deref(),
+            Some("5E3B1F9A0C7D44E2B1A8F06C93D25E7741B0CA9F")
+        );
+        assert_eq!(certificate(Some("not base64!")).thumbprint().as_deref(), Some("not base64!"));
+        assert_eq!(certificate(None).thumbprint(), None);
+    }
+}
@@ -0,0 +1,140 @@
+/*
+ * Licensed to the Apache Software Foundation (ASF) under one
+ * or more contributor license agreements.  See the NOTICE file
+ * distributed with this work for additional information
+ * regarding copyright ownership.  The ASF licenses this file
+ * to you under the Apache License, Version 2.0 (the
+ * "License"); you may not use this file except in compliance
+ * with the License.  You may obtain a copy of the License at
+ *
+ *   http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing,
+ * software distributed under the License is distributed on an
+ * "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+ * KIND, either express or implied.  See the License for the
+ * specific language governing permissions and limitations
+ * under the License.
+ */
+
+//! Lenient decoding of Azure objects, so that a single malformed object or credential
+//! does not fail decoding the whole response page it is in.
+
+use chrono::{DateTime, Utc};
+use serde::{de::DeserializeOwned, Deserialize, Deserializer, Serialize};
+use utoipa::ToSchema;
+
+/// An item of a response page that is either decoded or failed to decode
+#[derive(Debug)]
+pub struct Lenient<T>(pub Result<T, DecodeFailure>);
+
+/// An object that failed to decode and was skipped
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct DecodeFailure {
+    /// The ID of the object, if it has one
+    pub id: Option<String>,
+    pub error: String,
+}
+
+impl<'de, T: DeserializeOwned> Deserialize<'de> for Lenient<T> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let value = serde_json::Value::deserialize(deserializer)?;
+
+        Ok(Lenient(T::deserialize(&value).map_err(|e| DecodeFailure {
+            id: value["id"].as_str().map(String::from),
+            error: e.to_string(),
+        })))
+    }
+}
+
+/// A credential which, if it fails to decode, is kept with only the properties that did decode and the decode error
+pub trait LenientCredential: DeserializeOwned {
+    fn invalid(value: &serde_json::Value, error: String) -> Self;
+    fn key_id(&self) -> &str;
+    fn decode_error(&self) -> Option<&str>;
+    fn end_date_time(&self) -> Option<DateTime<Utc>>;
+}
+
+pub fn de_credentials<'de, D: Deserializer<'de>, T: LenientCredential>(deserializer: D) -> Result<Vec<T>, D::Error> {
+    let values = Vec::<serde_json::Value>::deserialize(deserializer)?;
+
+    Ok(values
+        .into_iter()
+        .map(|value| T::deserialize(&value).unwrap_or_else(|e| T::invalid(&value, e.to_string())))
+        .collect())
+}
+
+pub fn de_optional_credentials<'de, D: Deserializer<'de>, T: LenientCredential>(deserializer: D) -> Result<Option<Vec<T>>, D::Error> {
+    de_credentials(deserializer).map(Some)
+}
+
+/// Split decoded items from the ones that failed to decode
+pub fn partition<T>(items: Vec<Lenient<T>>) -> (Vec<T>, Vec<DecodeFailure>) {
+    let mut decoded = Vec::with_capacity(items.len());
+    let mut failures = Vec::new();
+
+    for Lenient(item) in items {
+        match item {
+            Ok(item) => decoded.push(item),
+            Err(failure) => failures.push(failure),
+        }
+    }
+
+    (decoded, failures)
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::types::applications::AzureApplication;
+
+    #[test]
+    fn malformed_applications_are_skipped_with_their_id() {
+        let page = json!([
+            {"id": "id1", "appId": "app1", "passwordCredentials": []},
+            {"id": "id2", "passwordCredentials": []},
+            "not an object",
+            {"id": "id3", "appId": "app3", "passwordCredentials": []},
+        ]);
+
+        let (decoded, failures) = partition(serde_json::from_value::<Vec<Lenient<AzureApplication>>>(page).unwrap());
+
+        assert_eq!(decoded.iter().map(|app| app.id.as_str()).collect::<Vec<_>>(), ["id1", "id3"]);
+        assert_eq!(
+            failures.iter().map(|failure| failure.id.as_deref()).collect::<Vec<_>>(),
+            [Some("id2"), None]
+        );
+        assert!(failures[0].error.contains("appId"), "{}", failures[0].error);
+    }
+
+    #[test]
+    fn malformed_credential_is_kept_with_its_decode_error() {
+        let application = json!({
+            "id": "id1",
+            "appId": "app1",
+            "passwordCredentials": [
+                {"keyId": "key1", "endDateTime": "2024-01-31T00:00:00Z"},
+                {"keyId": "key2", "displayName": "broken", "endDateTime": "not a date"},
+            ],
+            "keyCredentials": [{"keyId": "key3", "endDateTime": 42}],
+        });
+
+        let application: AzureApplication = serde_json::from_value(application).unwrap();
+
+        let [valid, invalid] = application.password_credentials.as_slice() else {
+            panic!("expected 2 password credentials");
+        };
+        assert_eq!(valid.decode_error, None);
+        assert_eq!(valid.end_date_time, Some("2024-01-31T00:00:00Z".parse().unwrap()));
+        assert_eq!(invalid.key_id, "key2");
+        assert_eq!(invalid.display_name.as_deref(), Some("broken"));
+        assert_eq!(invalid.end_date_time, None);
+        assert!(invalid.decode_error.is_some());
+
+        assert_eq!(application.key_credentials.len(), 1);
+        assert_eq!(application.key_credentials[0].key_id, "key3");
+        assert!(application.key_credentials[0].decode_error.is_some());
+    }
+}
@@ -18,3 +18,6 @@
  */
 
 pub mod applications;
+pub mod lenient;
+pub mod policies;
+pub mod service_principals;
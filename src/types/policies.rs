@@ -0,0 +1,264 @@
+/*
+ * Licensed to the Apache Software Foundation (ASF) under one
+ * or more contributor license agreements.  See the NOTICE file
+ * distributed with this work for additional information
+ * regarding copyright ownership.  The ASF licenses this file
+ * to you under the Apache License, Version 2.0 (the
+ * "License"); you may not use this file except in compliance
+ * with the License.  You may obtain a copy of the License at
+ *
+ *   http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing,
+ * software distributed under the License is distributed on an
+ * "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+ * KIND, either express or implied.  See the License for the
+ * specific language governing permissions and limitations
+ * under the License.
+ */
+
+use std::collections::HashMap;
+
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use utoipa::ToSchema;
+
+use crate::{
+    settings::app_settings::{format_duration, Policy},
+    types::applications::AzureApplication,
+};
+
+/// A rule of a policy that an application breaks
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct PolicyViolation {
+    pub policy: String,
+    pub tenant_id: String,
+    pub id: String,
+    pub app_id: String,
+    pub app_display_name: Option<String>,
+    /// Key ID of the password credential breaking the rule, if the violation is about a single credential
+    pub key_id: Option<String>,
+    pub reason: String,
+}
+
+/// Check the cached applications of a tenant against all policies at the given time, sorted by policy, application and credential
+pub fn evaluate_policies(
+    policies: &[Policy],
+    tenant_id: &str,
+    applications: &HashMap<String, AzureApplication>,
+    now: DateTime<Utc>,
+) -> Vec<PolicyViolation> {
+    let mut violations = Vec::new();
+
+    for policy in policies {
+        for app in applications.values().filter(|app| applies_to(policy, app)) {
+            let mut violation = |key_id: Option<&str>, reason: String| {
+                violations.push(PolicyViolation {
+                    policy: policy.name.clone(),
+                    tenant_id: tenant_id.to_string(),
+                    id: app.id.clone(),
+                    app_id: app.app_id.clone(),
+                    app_display_name: app.display_name.clone(),
+                    key_id: key_id.map(String::from),
+                    reason,
+                })
+            };
+
+            // Credentials that failed to decode have no dates to check
+            let passwords: Vec<_> = app
+                .password_credentials
+                .iter()
+                .filter(|password| password.decode_error.is_none())
+                .collect();
+            let active_passwords: Vec<_> = passwords.iter().filter(|password| password.is_active(now)).collect();
+
+            if let Some(max_lifetime) = policy.max_password_lifetime {
+                for password in &passwords {
+                    match (password.start_date_time, password.end_date_time) {
+                        (_, None) => violation(Some(&password.key_id), "password credential has no end date".to_string()),
+                        // Azure always sets a start date, so a credential without one cannot be shown to comply
+                        (None, Some(_)) => violation(
+                            Some(&password.key_id),
+                            "password credential has no start date, so its lifetime is unknown".to_string(),
+                        ),
+                        (Some(start_date_time), Some(end_date_time)) => {
+                            let lifetime = (end_date_time - start_date_time).to_std().unwrap_or_default();
+                            if lifetime > max_lifetime {
+                                violation(
+                                    Some(&password.key_id),
+                                    format!(
+                                        "password credential is valid for {}, longer than {}",
+                                        format_duration(lifetime),
+                                        format_duration(max_lifetime)
+                                    ),
+                                );
+                            }
+                        }
+                    }
+                }
+            }
+
+            if let Some(max_active_passwords) = policy.max_active_passwords {
+                if active_passwords.len() > max_active_passwords {
+                    violation(
+                        None,
+                        format!("{} active password credentials, more than {max_active_passwords}", active_passwords.len()),
+                    );
+                }
+            }
+
+            if policy.require_certificates {
+                for password in &active_passwords {
+                    violation(
+                        Some(&password.key_id),
+                        "active password credential, only certificates are allowed".to_string(),
+                    );
+                }
+
+                let has_active_certificate = app
+                    .key_credentials
+                    .iter()
+                    .any(|certificate| certificate.decode_error.is_none() && certificate.is_active(now));
+                if !has_active_certificate {
+                    violation(None, "no active certificate".to_string());
+                }
+            }
+        }
+    }
+
+    violations.sort_by(|a, b| (&a.policy, &a.id, &a.key_id).cmp(&(&b.policy, &b.id, &b.key_id)));
+    violations
+}
+
+/// Whether the policy checks the application, i.e. it matches the display name regex and app IDs of the policy
+pub fn applies_to(policy: &Policy, app: &AzureApplication) -> bool {
+    let display_name_matches = policy
+        .display_name_regex
+        .as_ref()
+        .is_none_or(|regex| regex.is_match(app.display_name.as_deref().unwrap_or_default()));
+
+    display_name_matches && (policy.app_ids.is_empty() || policy.app_ids.contains(&app.app_id))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn now() -> DateTime<Utc> {
+        "2025-01-01T00:00:00Z".parse().unwrap()
+    }
+
+    fn policy(rules: &str) -> Policy {
+        toml::from_str(&format!("name = \"test\"\n{rules}")).unwrap()
+    }
+
+    fn applications(app: serde_json::Value) -> HashMap<String, AzureApplication> {
+        let app: AzureApplication = serde_json::from_value(app).unwrap();
+        HashMap::from([(app.id.clone(), app)])
+    }
+
+    fn password(key_id: &str, start_date_time: Option<&str>, end_date_time: Option<&str>) -> serde_json::Value {
+        serde_json::json!({"keyId": key_id, "startDateTime": start_date_time, "endDateTime": end_date_time})
+    }
+
+    fn certificate(key_id: &str, end_date_time: &str) -> serde_json::Value {
+        serde_json::json!({"keyId": key_id, "startDateTime": "2024-01-01T00:00:00Z", "endDateTime": end_date_time})
+    }
+
+    fn app(passwords: Vec<serde_json::Value>, certificates: Vec<serde_json::Value>) -> HashMap<String, AzureApplication> {
+        applications(serde_json::json!({
+            "id": "id1",
+            "appId": "app1",
+            "displayName": "prod-app",
+            "passwordCredentials": passwords,
+            "keyCredentials": certificates,
+        }))
+    }
+
+    fn reasons(policy: &Policy, applications: &HashMap<String, AzureApplication>) -> Vec<(Option<String>, String)> {
+        evaluate_policies(std::slice::from_ref(policy), "t1", applications, now())
+            .into_iter()
+            .map(|violation| (violation.key_id, violation.reason))
+            .collect()
+    }
+
+    #[test]
+    fn max_password_lifetime() {
+        let policy = policy(r#"max_password_lifetime = "365d""#);
+        let applications = app(
+            vec![
+                password("short", Some("2024-06-01T00:00:00Z"), Some("2025-06-01T00:00:00Z")),
+                password("long", Some("2024-01-01T00:00:00Z"), Some("2026-01-01T00:00:00Z")),
+                password("no-end", Some("2024-01-01T00:00:00Z"), None),
+                password("no-start", None, Some("2025-06-01T00:00:00Z")),
+            ],
+            vec![],
+        );
+
+        assert_eq!(
+            reasons(&policy, &applications),
+            vec![
+                (Some("long".into()), "password credential is valid for 731d, longer than 365d".into()),
+                (Some("no-end".into()), "password credential has no end date".into()),
+                (
+                    Some("no-start".into()),
+                    "password credential has no start date, so its lifetime is unknown".into()
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn max_active_passwords() {
+        let policy = policy("max_active_passwords = 1");
+        let expired = password("expired", Some("2023-01-01T00:00:00Z"), Some("2024-01-01T00:00:00Z"));
+        let not_started = password("not-started", Some("2025-06-01T00:00:00Z"), Some("2026-01-01T00:00:00Z"));
+        let active = password("active", Some("2024-01-01T00:00:00Z"), Some("2026-01-01T00:00:00Z"));
+
+        let applications = app(vec![expired.clone(), not_started.clone(), active.clone()], vec![]);
+        assert!(reasons(&policy, &applications).is_empty());
+
+        let second_active = password("second-active", None, None);
+        let applications = app(vec![expired, not_started, active, second_active], vec![]);
+        assert_eq!(
+            reasons(&policy, &applications),
+            vec![(None, "2 active password credentials, more than 1".into())]
+        );
+    }
+
+    #[test]
+    fn require_certificates() {
+        let policy = policy("require_certificates = true\ndisplay_name_regex = \"^prod-\"");
+        let active_certificate = certificate("cert", "2026-01-01T00:00:00Z");
+        let expired_certificate = certificate("expired-cert", "2024-06-01T00:00:00Z");
+        let expired_password = password("expired", Some("2023-01-01T00:00:00Z"), Some("2024-01-01T00:00:00Z"));
+        let active_password = password("active", Some("2024-01-01T00:00:00Z"), Some("2026-01-01T00:00:00Z"));
+
+        let applications = app(vec![expired_password.clone()], vec![active_certificate.clone()]);
+        assert!(reasons(&policy, &applications).is_empty());
+
+        let applications = app(vec![active_password], vec![active_certificate]);
+        assert_eq!(
+            reasons(&policy, &applications),
+            vec![(Some("active".into()), "active password credential, only certificates are allowed".into())]
+        );
+
+        let applications = app(vec![], vec![]);
+        assert_eq!(reasons(&policy, &applications), vec![(None, "no active certificate".into())]);
+
+        let applications = app(vec![expired_password], vec![expired_certificate]);
+        assert_eq!(reasons(&policy, &applications), vec![(None, "no active certificate".into())]);
+    }
+
+    #[test]
+    fn policies_only_apply_to_matching_applications() {
+        let applications = app(vec![], vec![]);
+
+        assert!(reasons(&policy("require_certificates = true\ndisplay_name_regex = \"^dev-\""), &applications).is_empty());
+        assert!(reasons(&policy("require_certificates = true\napp_ids = [\"app2\"]"), &applications).is_empty());
+        assert_eq!(
+            reasons(&policy("require_certificates = true\napp_ids = [\"app1\"]"), &applications).len(),
+            1
+        );
+    }
+}
@@ -0,0 +1,52 @@
+/*
+ * Licensed to the Apache Software Foundation (ASF) under one
+ * or more contributor license agreements.  See the NOTICE file
+ * distributed with this work for additional information
+ * regarding copyright ownership.  The ASF licenses this file
+ * to you under the Apache License, Version 2.0 (the
+ * "License"); you may not use this file except in compliance
+ * with the License.  You may obtain a copy of the License at
+ *
+ *   http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing,
+ * software distributed under the License is distributed on an
+ * "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+ * KIND, either express or implied.  See the License for the
+ * specific language governing permissions and limitations
+ * under the License.
+ */
+
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+use crate::types::{
+    applications::{KeyCredential, PasswordCredential},
+    lenient::{de_credentials, Lenient},
+};
+
+/// https://learn.microsoft.com/en-us/graph/api/resources/serviceprincipal?view=graph-rest-1.0#properties
+#[derive(Debug, Deserialize)]
+pub struct AzureServicePrincipals {
+    #[serde(rename = "@odata.nextLink")]
+    pub next_link: Option<String>,
+    pub value: Vec<Lenient<AzureServicePrincipal>>,
+}
+
+#[derive(Debug, Deserialize, Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct AzureServicePrincipal {
+    pub id: String,
+    pub app_id: String,
+    pub display_name: Option<String>,
+    /// Usually "Application", "ManagedIdentity" or "Legacy"
+    pub service_principal_type: Option<String>,
+    /// Thumbprint of the certificate used to sign SAML tokens for apps configured with SAML single sign-on
+    pub preferred_token_signing_key_thumbprint: Option<String>,
+    #[serde(deserialize_with = "de_credentials", default)]
+    #[schema(inline)]
+    pub password_credentials: Vec<PasswordCredential>,
+    #[serde(deserialize_with = "de_credentials", default)]
+    #[schema(inline)]
+    pub key_credentials: Vec<KeyCredential>,
+}
@@ -0,0 +1,42 @@
+/*
+ * Licensed to the Apache Software Foundation (ASF) under one
+ * or more contributor license agreements.  See the NOTICE file
+ * distributed with this work for additional information
+ * regarding copyright ownership.  The ASF licenses this file
+ * to you under the Apache License, Version 2.0 (the
+ * "License"); you may not use this file except in compliance
+ * with the License.  You may obtain a copy of the License at
+ *
+ *   http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing,
+ * software distributed under the License is distributed on an
+ * "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+ * KIND, either express or implied.  See the License for the
+ * specific language governing permissions and limitations
+ * under the License.
+ */
+
+//! Response headers describing the freshness of the cache an API response was served from,
+//! so that API clients can tell whether they got the last known good data of a failing refresh.
+
+use axum::http::{HeaderMap, HeaderName, HeaderValue};
+
+use crate::global_state::CacheStatus;
+
+static LAST_SUCCESS_HEADER_NAME: HeaderName = HeaderName::from_static("x-cache-last-success");
+static CONSECUTIVE_FAILURES_HEADER_NAME: HeaderName = HeaderName::from_static("x-cache-consecutive-failures");
+static STALE_HEADER_NAME: HeaderName = HeaderName::from_static("x-cache-stale");
+
+pub fn cache_status_headers(status: &CacheStatus) -> HeaderMap {
+    let mut headers = HeaderMap::new();
+
+    if let Some(last_success) = status.last_success {
+        let last_success = HeaderValue::from_str(&last_success.to_rfc3339()).expect("rfc 3339 dates are valid header values");
+        headers.insert(&LAST_SUCCESS_HEADER_NAME, last_success);
+    }
+    headers.insert(&CONSECUTIVE_FAILURES_HEADER_NAME, status.consecutive_failures.into());
+    headers.insert(&STALE_HEADER_NAME, HeaderValue::from_static(if status.stale { "true" } else { "false" }));
+
+    headers
+}
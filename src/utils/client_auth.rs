@@ -0,0 +1,388 @@
+/*
+ * Licensed to the Apache Software Foundation (ASF) under one
+ * or more contributor license agreements.  See the NOTICE file
+ * distributed with this work for additional information
+ * regarding copyright ownership.  The ASF licenses this file
+ * to you under the Apache License, Version 2.0 (the
+ * "License"); you may not use this file except in compliance
+ * with the License.  You may obtain a copy of the License at
+ *
+ *   http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing,
+ * software distributed under the License is distributed on an
+ * "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+ * KIND, either express or implied.  See the License for the
+ * specific language governing permissions and limitations
+ * under the License.
+ */
+
+//! Mutual TLS, i.e. verifying the certificates of clients like Prometheus against a client CA and an allowlist of names.
+
+use std::{future::Future, io, net::IpAddr, path::Path, pin::Pin, sync::Arc, time::SystemTime};
+
+use axum_server::{
+    accept::Accept,
+    tls_rustls::{RustlsAcceptor, RustlsConfig},
+};
+use rustls::{
+    server::{AllowAnyAnonymousOrAuthenticatedClient, AllowAnyAuthenticatedClient, ClientCertVerified, ClientCertVerifier},
+    CertificateError, DistinguishedName, RootCertStore,
+};
+use x509_parser::prelude::{FromDer, GeneralName, X509Certificate};
+
+use crate::{
+    app_metrics::TLS_REJECTED_HANDSHAKES_TOTAL,
+    settings::app_settings::{ClientAuth, Tls},
+};
+
+/// Build the verifier of client certificates from the `[tls]` settings, or `None` if no client CA file is set
+pub fn client_cert_verifier(tls_settings: &Tls) -> Result<Option<Arc<dyn ClientCertVerifier>>, String> {
+    let Some(client_ca_file) = &tls_settings.client_ca_file else {
+        if !tls_settings.allowed_client_names.is_empty() {
+            return Err("allowed_client_names requires a client_ca_file".into());
+        }
+
+        return Ok(None);
+    };
+
+    let roots = load_client_ca(client_ca_file)?;
+
+    let verifier = match tls_settings.client_auth {
+        ClientAuth::Required => AllowAnyAuthenticatedClient::new(roots).boxed(),
+        ClientAuth::Optional => AllowAnyAnonymousOrAuthenticatedClient::new(roots).boxed(),
+    };
+
+    if tls_settings.allowed_client_names.is_empty() {
+        return Ok(Some(verifier));
+    }
+
+    Ok(Some(Arc::new(AllowlistVerifier {
+        inner: verifier,
+        allowed_client_names: tls_settings.allowed_client_names.clone(),
+    })))
+}
+
+fn load_client_ca(client_ca_file: &Path) -> Result<RootCertStore, String> {
+    let contents = std::fs::read(client_ca_file).map_err(|e| format!("failed reading tls client ca file {}: {e}", client_ca_file.display()))?;
+
+    let ca_certs = rustls_pemfile::certs(&mut &*contents)
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| format!("invalid PEM in tls client ca file {}: {e}", client_ca_file.display()))?;
+
+    let mut roots = RootCertStore::empty();
+    let (valid, invalid) = roots.add_parsable_certificates(&ca_certs);
+
+    if valid == 0 {
+        return Err(format!("tls client ca file {} has no valid certificate", client_ca_file.display()));
+    }
+    if invalid > 0 {
+        tracing::warn!(
+            path = %client_ca_file.display(),
+            invalid,
+            "ignoring invalid certificates in tls client ca file"
+        );
+    }
+
+    Ok(roots)
+}
+
+/// Verify the client certificate with the inner verifier, then check its subject and subject alternative names against the allowlist
+struct AllowlistVerifier {
+    inner: Arc<dyn ClientCertVerifier>,
+    allowed_client_names: Vec<String>,
+}
+
+impl ClientCertVerifier for AllowlistVerifier {
+    fn offer_client_auth(&self) -> bool {
+        self.inner.offer_client_auth()
+    }
+
+    fn client_auth_mandatory(&self) -> bool {
+        self.inner.client_auth_mandatory()
+    }
+
+    fn client_auth_root_subjects(&self) -> &[DistinguishedName] {
+        self.inner.client_auth_root_subjects()
+    }
+
+    fn verify_client_cert(
+        &self,
+        end_entity: &rustls::Certificate,
+        intermediates: &[rustls::Certificate],
+        now: SystemTime,
+    ) -> Result<ClientCertVerified, rustls::Error> {
+        let verified = self.inner.verify_client_cert(end_entity, intermediates, now)?;
+
+        let (_, certificate) =
+            X509Certificate::from_der(&end_entity.0).map_err(|_| rustls::Error::InvalidCertificate(CertificateError::BadEncoding))?;
+        let names = certificate_names(&certificate);
+
+        if !names.iter().any(|name| self.allowed_client_names.contains(name)) {
+            tracing::warn!(names = names.join(", "), "tls client certificate is not in allowed_client_names");
+            return Err(rustls::Error::InvalidCertificate(CertificateError::ApplicationVerificationFailure));
+        }
+
+        Ok(verified)
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &rustls::Certificate,
+        dss: &rustls::DigitallySignedStruct,
+    ) -> Result<rustls::client::HandshakeSignatureValid, rustls::Error> {
+        self.inner.verify_tls12_signature(message, cert, dss)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &rustls::Certificate,
+        dss: &rustls::DigitallySignedStruct,
+    ) -> Result<rustls::client::HandshakeSignatureValid, rustls::Error> {
+        self.inner.verify_tls13_signature(message, cert, dss)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<rustls::SignatureScheme> {
+        self.inner.supported_verify_schemes()
+    }
+}
+
+/// The subject and the DNS, URI, email and IP subject alternative names of the certificate
+fn certificate_names(certificate: &X509Certificate) -> Vec<String> {
+    let mut names = vec![certificate.subject().to_string()];
+
+    if let Ok(Some(subject_alternative_names)) = certificate.subject_alternative_name() {
+        for name in subject_alternative_names.value.general_names.iter() {
+            match name {
+                GeneralName::DNSName(name) | GeneralName::URI(name) | GeneralName::RFC822Name(name) => names.push(name.to_string()),
+                GeneralName::IPAddress(ip) => {
+                    if let Ok(ip) = <[u8; 4]>::try_from(*ip)
+                        .map(IpAddr::from)
+                        .or_else(|_| <[u8; 16]>::try_from(*ip).map(IpAddr::from))
+                    {
+                        names.push(ip.to_string());
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    names
+}
+
+/// Accept TLS connections like [`RustlsAcceptor`], counting the handshakes that were rejected because of the client's certificate
+#[derive(Clone)]
+pub struct ClientAuthAcceptor {
+    inner: RustlsAcceptor,
+    /// The listen address of the listener, for the metrics
+    listener: String,
+}
+
+impl ClientAuthAcceptor {
+    pub fn new(config: RustlsConfig, listener: String) -> Self {
+        Self {
+            inner: RustlsAcceptor::new(config),
+            listener,
+        }
+    }
+}
+
+impl<I, S> Accept<I, S> for ClientAuthAcceptor
+where
+    RustlsAcceptor: Accept<I, S>,
+    <RustlsAcceptor as Accept<I, S>>::Future: Send + 'static,
+{
+    type Stream = <RustlsAcceptor as Accept<I, S>>::Stream;
+    type Service = <RustlsAcceptor as Accept<I, S>>::Service;
+    type Future = Pin<Box<dyn Future<Output = io::Result<(Self::Stream, Self::Service)>> + Send>>;
+
+    fn accept(&self, stream: I, service: S) -> Self::Future {
+        let future = self.inner.accept(stream, service);
+        let listener = self.listener.clone();
+
+        Box::pin(async move { future.await.inspect_err(|e| count_rejected_handshake(listener, e)) })
+    }
+}
+
+fn count_rejected_handshake(listener: String, e: &io::Error) {
+    // The handshake errors of rustls are wrapped in IO errors
+    let Some(e) = e.get_ref().and_then(|e| e.downcast_ref::<rustls::Error>()) else {
+        return;
+    };
+
+    let reason = match e {
+        rustls::Error::NoCertificatesPresented => "no_certificate",
+        rustls::Error::InvalidCertificate(CertificateError::ApplicationVerificationFailure) => "not_allowed",
+        rustls::Error::InvalidCertificate(_) => "invalid_certificate",
+        _ => return,
+    };
+
+    tracing::warn!(listener, reason, error = %e, "rejected tls client certificate");
+    metrics::counter!(TLS_REJECTED_HANDSHAKES_TOTAL, &[("listener", listener), ("reason", reason.into())]).increment(1);
+}
+
+#[cfg(test)]
+mod tests {
+    use rustls::{ClientConfig, ClientConnection, Connection, ServerConfig, ServerConnection};
+
+    use super::*;
+
+    // Test certificates signed by testdata/ca.pem, except other_client.pem, generated with openssl and valid until 2125
+    const CA_FILE: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/src/utils/testdata/ca.pem");
+    const CLIENT: (&[u8], &[u8]) = (include_bytes!("testdata/client.pem"), include_bytes!("testdata/client.key"));
+    const OTHER_CLIENT: (&[u8], &[u8]) = (include_bytes!("testdata/other_client.pem"), include_bytes!("testdata/other_client.key"));
+    const SERVER: (&[u8], &[u8]) = (include_bytes!("testdata/localhost.pem"), include_bytes!("testdata/localhost.key"));
+
+    fn cert_and_key((cert, key): (&[u8], &[u8])) -> (Vec<rustls::Certificate>, rustls::PrivateKey) {
+        let certs = rustls_pemfile::certs(&mut &*cert)
+            .map(|cert| rustls::Certificate(cert.unwrap().to_vec()))
+            .collect();
+        let key = rustls_pemfile::ec_private_keys(&mut &*key).next().unwrap().unwrap();
+        (certs, rustls::PrivateKey(key.secret_sec1_der().to_vec()))
+    }
+
+    fn tls(client_auth: &str, allowed_client_names: &[&str]) -> Tls {
+        toml::from_str(&format!(
+            "client_ca_file = {CA_FILE:?}\nclient_auth = {client_auth:?}\nallowed_client_names = {allowed_client_names:?}"
+        ))
+        .unwrap()
+    }
+
+    /// Run a TLS handshake in memory and return the error of the server, if it rejected the client
+    fn handshake(tls_settings: &Tls, client_cert: Option<(&[u8], &[u8])>) -> Result<(), rustls::Error> {
+        let (server_certs, server_key) = cert_and_key(SERVER);
+        let server_config = ServerConfig::builder()
+            .with_safe_defaults()
+            .with_client_cert_verifier(client_cert_verifier(tls_settings).unwrap().unwrap())
+            .with_single_cert(server_certs, server_key)
+            .unwrap();
+
+        let mut roots = RootCertStore::empty();
+        let ca = std::fs::read(CA_FILE).unwrap();
+        roots.add_parsable_certificates(&rustls_pemfile::certs(&mut &*ca).collect::<Result<Vec<_>, _>>().unwrap());
+        let client_config = ClientConfig::builder().with_safe_defaults().with_root_certificates(roots);
+        let client_config = match client_cert {
+            Some(client_cert) => {
+                let (client_certs, client_key) = cert_and_key(client_cert);
+                client_config.with_client_auth_cert(client_certs, client_key).unwrap()
+            }
+            None => client_config.with_no_client_auth(),
+        };
+
+        let mut client = Connection::from(ClientConnection::new(Arc::new(client_config), "localhost".try_into().unwrap()).unwrap());
+        let mut server = Connection::from(ServerConnection::new(Arc::new(server_config)).unwrap());
+
+        while client.is_handshaking() || server.is_handshaking() {
+            transfer(&mut client, &mut server)?;
+            transfer(&mut server, &mut client).expect("the client accepts the server");
+        }
+
+        Ok(())
+    }
+
+    fn transfer(from: &mut Connection, to: &mut Connection) -> Result<(), rustls::Error> {
+        let mut buffer = Vec::new();
+        while from.wants_write() {
+            from.write_tls(&mut buffer).unwrap();
+        }
+
+        let mut buffer = buffer.as_slice();
+        while !buffer.is_empty() {
+            to.read_tls(&mut buffer).unwrap();
+            to.process_new_packets()?;
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn certificate_names_are_the_subject_and_subject_alternative_names() {
+        let (certs, _) = cert_and_key(CLIENT);
+        let (_, certificate) = X509Certificate::from_der(&certs[0].0).unwrap();
+
+        assert_eq!(
+            certificate_names(&certificate),
+            [
+                "CN=prometheus",
+                "prometheus.example.com",
+                "spiffe://example.com/prometheus",
+                "prometheus@example.com",
+                "10.0.0.1",
+                "::1"
+            ]
+        );
+    }
+
+    #[test]
+    fn allowlist_matches_the_subject_or_a_subject_alternative_name() {
+        for allowed_client_name in ["CN=prometheus", "prometheus.example.com", "10.0.0.1"] {
+            let tls_settings = tls("required", &[allowed_client_name]);
+            assert_eq!(handshake(&tls_settings, Some(CLIENT)), Ok(()), "{allowed_client_name}");
+        }
+
+        let tls_settings = tls("required", &["CN=grafana", "grafana.example.com"]);
+        assert_eq!(
+            handshake(&tls_settings, Some(CLIENT)),
+            Err(rustls::Error::InvalidCertificate(CertificateError::ApplicationVerificationFailure))
+        );
+    }
+
+    #[test]
+    fn allowlist_does_not_allow_certificates_of_another_ca() {
+        let tls_settings = tls("required", &["CN=prometheus"]);
+        assert!(matches!(
+            handshake(&tls_settings, Some(OTHER_CLIENT)),
+            Err(rustls::Error::InvalidCertificate(e)) if e != CertificateError::ApplicationVerificationFailure
+        ));
+    }
+
+    #[test]
+    fn optional_client_auth_allows_clients_without_a_certificate() {
+        let tls_settings = tls("optional", &["CN=prometheus"]);
+        assert_eq!(handshake(&tls_settings, None), Ok(()));
+        assert_eq!(handshake(&tls_settings, Some(CLIENT)), Ok(()));
+
+        // A presented certificate is still checked against the allowlist
+        let tls_settings = tls("optional", &["CN=grafana"]);
+        assert_eq!(
+            handshake(&tls_settings, Some(CLIENT)),
+            Err(rustls::Error::InvalidCertificate(CertificateError::ApplicationVerificationFailure))
+        );
+
+        let tls_settings = tls("required", &["CN=prometheus"]);
+        assert_eq!(handshake(&tls_settings, None), Err(rustls::Error::NoCertificatesPresented));
+    }
+
+    #[test]
+    fn rejected_handshakes_are_counted_by_reason() {
+        let recorder = metrics_exporter_prometheus::PrometheusBuilder::new().build_recorder();
+        let handle = recorder.handle();
+
+        metrics::with_local_recorder(&recorder, || {
+            for (tls_settings, client_cert) in [
+                (tls("required", &["CN=prometheus"]), None),
+                (tls("required", &["CN=grafana"]), Some(CLIENT)),
+                (tls("required", &["CN=prometheus"]), Some(OTHER_CLIENT)),
+            ] {
+                let e = handshake(&tls_settings, client_cert).unwrap_err();
+                count_rejected_handshake("127.0.0.1:8443".into(), &io::Error::new(io::ErrorKind::InvalidData, e));
+            }
+
+            // Other errors, like a client speaking plain HTTP, are not about the client certificate
+            count_rejected_handshake(
+                "127.0.0.1:8443".into(),
+                &io::Error::new(io::ErrorKind::InvalidData, rustls::Error::DecryptError),
+            );
+        });
+
+        let rendered = handle.render();
+        for reason in ["no_certificate", "not_allowed", "invalid_certificate"] {
+            let sample = format!("{TLS_REJECTED_HANDSHAKES_TOTAL}{{listener=\"127.0.0.1:8443\",reason=\"{reason}\"}} 1\n");
+            assert!(rendered.contains(&sample), "{rendered}");
+        }
+        assert_eq!(rendered.matches(&format!("{TLS_REJECTED_HANDSHAKES_TOTAL}{{")).count(), 3, "{rendered}");
+    }
+}
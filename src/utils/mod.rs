@@ -17,6 +17,20 @@
  * under the License.
  */
 
+pub mod cache_status_headers;
+pub mod client_auth;
+pub mod client_certificate;
 pub mod from_swagger_ui_header;
+pub mod retry;
+pub mod rw_lock;
+pub mod sockets;
+pub mod tls_config;
 
+pub use cache_status_headers::*;
+pub use client_auth::*;
+pub use client_certificate::*;
 pub use from_swagger_ui_header::*;
+pub use retry::*;
+pub use rw_lock::*;
+pub use sockets::*;
+pub use tls_config::*;
@@ -0,0 +1,189 @@
+/*
+ * Licensed to the Apache Software Foundation (ASF) under one
+ * or more contributor license agreements.  See the NOTICE file
+ * distributed with this work for additional information
+ * regarding copyright ownership.  The ASF licenses this file
+ * to you under the Apache License, Version 2.0 (the
+ * "License"); you may not use this file except in compliance
+ * with the License.  You may obtain a copy of the License at
+ *
+ *   http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing,
+ * software distributed under the License is distributed on an
+ * "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+ * KIND, either express or implied.  See the License for the
+ * specific language governing permissions and limitations
+ * under the License.
+ */
+
+//! Send requests to Azure with retries on throttling and other transient failures.
+//!
+//! Throttled requests (429 and 503) are retried after the duration in their `Retry-After` header,
+//! and all other transient failures are retried with exponential backoff and jitter.
+//!
+//! https://learn.microsoft.com/en-us/graph/throttling
+
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use reqwest::{header::RETRY_AFTER, RequestBuilder, Response, StatusCode};
+
+use crate::{
+    app_metrics::{AZURE_REQUEST_RETRIES_TOTAL, AZURE_THROTTLED_REQUESTS_TOTAL},
+    settings::app_settings::Retry,
+};
+
+/// Send the request, retrying it up to `max_retries` times.
+///
+/// Like [`RequestBuilder::send`], non-transient error statuses are not turned into errors, so callers should still check the status.
+/// If the retries are exhausted, the last response or error is returned.
+pub async fn send_with_retries(request: RequestBuilder, retry: &Retry, tenant_id: &str, endpoint: &'static str) -> reqwest::Result<Response> {
+    let mut attempt = 0;
+
+    loop {
+        // Requests with streaming bodies cannot be cloned and therefore not retried
+        let Some(attempt_request) = request.try_clone() else {
+            return request.send().await;
+        };
+
+        let result = attempt_request.send().await;
+
+        let (reason, retry_after) = match &result {
+            Ok(response) if matches!(response.status(), StatusCode::TOO_MANY_REQUESTS | StatusCode::SERVICE_UNAVAILABLE) => {
+                metrics::counter!(
+                    AZURE_THROTTLED_REQUESTS_TOTAL,
+                    &[
+                        ("tenant_id", tenant_id.to_string()),
+                        ("endpoint", endpoint.to_string()),
+                        ("status", response.status().as_u16().to_string())
+                    ]
+                )
+                .increment(1);
+
+                ("throttled", parse_retry_after(response, retry.max_backoff))
+            }
+            Ok(response) if response.status().is_server_error() => ("server_error", None),
+            Ok(_) => return result,
+            Err(e) if e.is_builder() => return result,
+            Err(_) => ("network", None),
+        };
+
+        if attempt >= retry.max_retries {
+            return result;
+        }
+
+        let retry_in = retry_after.unwrap_or_else(|| backoff(retry, attempt));
+        let retry_in_millis = retry_in.as_millis() as u64;
+        attempt += 1;
+
+        match &result {
+            Ok(response) => tracing::warn!(
+                tenant_id,
+                endpoint,
+                attempt,
+                status = response.status().as_u16(),
+                retry_in_millis,
+                "retrying azure request"
+            ),
+            Err(e) => tracing::warn!(
+                tenant_id,
+                endpoint,
+                attempt,
+                error = e as &dyn std::error::Error,
+                retry_in_millis,
+                "retrying azure request"
+            ),
+        }
+
+        metrics::counter!(
+            AZURE_REQUEST_RETRIES_TOTAL,
+            &[
+                ("tenant_id", tenant_id.to_string()),
+                ("endpoint", endpoint.to_string()),
+                ("reason", reason.to_string())
+            ]
+        )
+        .increment(1);
+
+        tokio::time::sleep(retry_in).await;
+    }
+}
+
+/// Graph sends the number of seconds to wait in the `Retry-After` header. Capped at `max_backoff`, so a bogus header cannot stall a refresh
+fn parse_retry_after(response: &Response, max_backoff: Duration) -> Option<Duration> {
+    let retry_after = response.headers().get(RETRY_AFTER)?.to_str().ok()?;
+    retry_after_duration(retry_after, Utc::now()).map(|retry_after| retry_after.min(max_backoff))
+}
+
+/// `Retry-After` is either a number of seconds or an HTTP date, which has already passed if the clocks are off
+fn retry_after_duration(retry_after: &str, now: DateTime<Utc>) -> Option<Duration> {
+    let retry_after = retry_after.trim();
+
+    if let Ok(seconds) = retry_after.parse() {
+        return Some(Duration::from_secs(seconds));
+    }
+
+    let date = DateTime::parse_from_rfc2822(retry_after).ok()?;
+    Some((date.with_timezone(&Utc) - now).to_std().unwrap_or_default())
+}
+
+/// Exponential backoff with "equal jitter", i.e. a random duration between half and all of the exponential delay
+fn backoff(retry: &Retry, attempt: u32) -> Duration {
+    let delay = retry.initial_backoff.saturating_mul(2u32.saturating_pow(attempt)).min(retry.max_backoff);
+
+    delay.mul_f64(0.5 + fastrand::f64() / 2.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn retry() -> Retry {
+        Retry {
+            max_retries: 5,
+            initial_backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(60),
+        }
+    }
+
+    #[test]
+    fn backoff_doubles_with_equal_jitter_up_to_max_backoff() {
+        let retry = retry();
+
+        for (attempt, delay) in [(0, 1), (1, 2), (2, 4), (5, 32), (6, 60), (31, 60), (u32::MAX, 60)] {
+            let delay = Duration::from_secs(delay);
+            for _ in 0..100 {
+                let backoff = backoff(&retry, attempt);
+                assert!(backoff >= delay / 2 && backoff <= delay, "attempt {attempt}: {backoff:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn retry_after_is_seconds_or_http_date() {
+        let now = "2024-01-01T00:00:00Z".parse().unwrap();
+
+        assert_eq!(retry_after_duration("120", now), Some(Duration::from_secs(120)));
+        assert_eq!(retry_after_duration(" 0 ", now), Some(Duration::ZERO));
+        assert_eq!(retry_after_duration("Mon, 01 Jan 2024 00:00:30 GMT", now), Some(Duration::from_secs(30)));
+        assert_eq!(retry_after_duration("Sun, 31 Dec 2023 23:59:00 GMT", now), Some(Duration::ZERO));
+        assert_eq!(retry_after_duration("soon", now), None);
+        assert_eq!(retry_after_duration("-1", now), None);
+    }
+
+    #[test]
+    fn retry_after_is_capped_at_max_backoff() {
+        let response = |retry_after: &str| Response::from(axum::http::Response::builder().header(RETRY_AFTER, retry_after).body("").unwrap());
+
+        assert_eq!(parse_retry_after(&response("30"), Duration::from_secs(60)), Some(Duration::from_secs(30)));
+        assert_eq!(
+            parse_retry_after(&response("86400"), Duration::from_secs(60)),
+            Some(Duration::from_secs(60))
+        );
+        assert_eq!(
+            parse_retry_after(&Response::from(axum::http::Response::new("")), Duration::from_secs(60)),
+            None
+        );
+    }
+}
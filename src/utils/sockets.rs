@@ -0,0 +1,315 @@
+/*
+ * Licensed to the Apache Software Foundation (ASF) under one
+ * or more contributor license agreements.  See the NOTICE file
+ * distributed with this work for additional information
+ * regarding copyright ownership.  The ASF licenses this file
+ * to you under the Apache License, Version 2.0 (the
+ * "License"); you may not use this file except in compliance
+ * with the License.  You may obtain a copy of the License at
+ *
+ *   http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing,
+ * software distributed under the License is distributed on an
+ * "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+ * KIND, either express or implied.  See the License for the
+ * specific language governing permissions and limitations
+ * under the License.
+ */
+
+use std::{
+    fs::DirBuilder,
+    io,
+    os::{
+        fd::{FromRawFd, OwnedFd},
+        unix::{
+            fs::{DirBuilderExt, FileTypeExt, PermissionsExt},
+            net::UnixListener,
+        },
+    },
+    path::Path,
+    time::Duration,
+};
+
+use axum::Router;
+use axum_server::accept::Accept;
+use hyper_util::{
+    rt::{TokioExecutor, TokioIo},
+    server::conn::auto,
+    service::TowerToHyperService,
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    sync::watch,
+    task::JoinSet,
+};
+
+use crate::{
+    settings::{app_settings::Listener, listen_address::ListenAddress},
+    utils::ClientAuthAcceptor,
+};
+
+/// A socket a listener accepts connections on
+pub enum ListenSocket {
+    Tcp(std::net::TcpListener),
+    Unix(UnixListener),
+}
+
+impl ListenSocket {
+    fn listen_address(&self) -> Option<ListenAddress> {
+        match self {
+            ListenSocket::Tcp(listener) => listener.local_addr().ok().map(ListenAddress::Tcp),
+            ListenSocket::Unix(listener) => listener
+                .local_addr()
+                .ok()
+                .and_then(|address| address.as_pathname().map(|path| ListenAddress::Unix(path.into()))),
+        }
+    }
+}
+
+/// The sockets passed by systemd with socket activation (`LISTEN_FDS`), along with the addresses they are bound to
+fn inherited_sockets() -> Result<Vec<(ListenAddress, ListenSocket)>, String> {
+    let fds = sd_notify::listen_fds().map_err(|e| format!("invalid socket activation env vars: {e}"))?;
+
+    let mut sockets = Vec::new();
+    for fd in fds {
+        // SAFETY: systemd passes the sockets as the file descriptors from LISTEN_FDS on, which nothing else in the process owns
+        let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+
+        let socket = inherited_socket(fd)?;
+        match socket.listen_address() {
+            Some(listen_address) => sockets.push((listen_address, socket)),
+            None => tracing::warn!("ignoring an inherited socket which is neither a tcp nor a unix domain socket with a path"),
+        }
+    }
+
+    Ok(sockets)
+}
+
+/// Use a socket passed by systemd as a TCP or Unix domain socket listener. Datagram and sequential packet sockets, e.g. from `ListenDatagram=`,
+/// are rejected, since they would otherwise pass for a TCP socket with a local address and fail on the first accept
+fn inherited_socket(fd: OwnedFd) -> Result<ListenSocket, String> {
+    let socket = socket2::Socket::from(fd);
+
+    match socket.r#type() {
+        Ok(socket2::Type::STREAM) => {}
+        Ok(_) => return Err("a socket passed by systemd is not a stream socket, only ListenStream= sockets are supported".into()),
+        Err(e) => return Err(format!("failed getting the type of a socket passed by systemd: {e}")),
+    }
+
+    // Getting the local address of a Unix domain socket as a TCP socket fails since it has another address family
+    let tcp_listener = std::net::TcpListener::from(socket);
+    Ok(match tcp_listener.local_addr() {
+        Ok(_) => ListenSocket::Tcp(tcp_listener),
+        Err(_) => ListenSocket::Unix(UnixListener::from(OwnedFd::from(tcp_listener))),
+    })
+}
+
+/// Bind the sockets of the listeners, in the same order. Listeners with the address of a socket passed by systemd use that socket instead.
+/// This is done at startup before anything else, so an address in use or a bad socket path fails the startup
+pub fn bind_listeners(listeners: &[Listener]) -> Result<Vec<ListenSocket>, String> {
+    let mut inherited_sockets = inherited_sockets()?;
+
+    let sockets = listeners
+        .iter()
+        .map(|listener| bind(listener, &mut inherited_sockets))
+        .collect::<Result<_, _>>()?;
+
+    for (listen_address, _) in inherited_sockets {
+        tracing::warn!(listener = %listen_address, "no listener has the address of a socket passed by systemd, ignoring the socket");
+    }
+
+    Ok(sockets)
+}
+
+/// Take the inherited socket bound to the address of the listener, or bind a new one
+fn bind(listener: &Listener, inherited_sockets: &mut Vec<(ListenAddress, ListenSocket)>) -> Result<ListenSocket, String> {
+    let socket = match inherited_sockets.iter().position(|(address, _)| *address == listener.listen_address) {
+        Some(i) => {
+            tracing::info!(listener = %listener.listen_address, "using the socket passed by systemd");
+            inherited_sockets.swap_remove(i).1
+        }
+        None => match &listener.listen_address {
+            ListenAddress::Tcp(address) => {
+                ListenSocket::Tcp(std::net::TcpListener::bind(address).map_err(|e| format!("failed binding {address}: {e}"))?)
+            }
+            ListenAddress::Unix(path) => ListenSocket::Unix(bind_unix(path, listener.socket_mode)?),
+        },
+    };
+
+    match &socket {
+        ListenSocket::Tcp(listener) => listener.set_nonblocking(true),
+        ListenSocket::Unix(listener) => listener.set_nonblocking(true),
+    }
+    .map_err(|e| format!("failed setting {} to non-blocking: {e}", listener.listen_address))?;
+
+    Ok(socket)
+}
+
+fn bind_unix(path: &Path, socket_mode: Option<u32>) -> Result<UnixListener, String> {
+    // A socket left behind by a previous run makes binding fail, but never remove anything else which happens to be at the path
+    if let Ok(metadata) = std::fs::symlink_metadata(path) {
+        if !metadata.file_type().is_socket() {
+            return Err(format!("failed binding {}: the path exists and is not a socket", path.display()));
+        }
+        std::fs::remove_file(path).map_err(|e| format!("failed removing stale socket {}: {e}", path.display()))?;
+    }
+
+    let Some(socket_mode) = socket_mode else {
+        return UnixListener::bind(path).map_err(|e| format!("failed binding {}: {e}", path.display()));
+    };
+
+    // Binding creates the socket with the permissions of the umask, so bind it in a directory only we can access and move it into place
+    // once its permissions are set. Otherwise clients could connect before the permissions are restricted to the socket mode
+    let file_name = path
+        .file_name()
+        .ok_or_else(|| format!("failed binding {}: the path has no file name", path.display()))?;
+    let private_dir = path.with_file_name(format!(".{}.tmp", file_name.to_string_lossy()));
+    let _ = std::fs::remove_dir_all(&private_dir);
+    DirBuilder::new()
+        .mode(0o700)
+        .create(&private_dir)
+        .map_err(|e| format!("failed creating directory {} to bind {} in: {e}", private_dir.display(), path.display()))?;
+
+    let private_path = private_dir.join(file_name);
+    let result = UnixListener::bind(&private_path)
+        .map_err(|e| format!("failed binding {}: {e}", path.display()))
+        .and_then(|listener| {
+            std::fs::set_permissions(&private_path, std::fs::Permissions::from_mode(socket_mode))
+                .map_err(|e| format!("failed setting the permissions of {}: {e}", path.display()))?;
+            std::fs::rename(&private_path, path).map_err(|e| format!("failed moving socket {} into place: {e}", path.display()))?;
+            Ok(listener)
+        });
+
+    let _ = std::fs::remove_dir_all(&private_dir);
+    result
+}
+
+/// Serve the router on a Unix domain socket, since axum-server only serves TCP sockets.
+/// Once the shutdown receiver gets the drain timeout, no new connections are accepted and in-flight requests get until the timeout to finish
+pub async fn serve_unix(
+    listener: UnixListener,
+    acceptor: Option<ClientAuthAcceptor>,
+    router: Router,
+    mut shutdown: watch::Receiver<Option<Duration>>,
+) -> io::Result<()> {
+    let listener = tokio::net::UnixListener::from_std(listener)?;
+    let mut connections = JoinSet::new();
+
+    loop {
+        let stream = tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok((stream, _)) => stream,
+                Err(e) => {
+                    tracing::warn!(error = %e, "failed accepting connection on unix socket");
+                    continue;
+                }
+            },
+            // Reap finished connections so they don't pile up
+            Some(_) = connections.join_next() => continue,
+            _ = shutdown.changed() => break,
+        };
+
+        let router = router.clone();
+        let acceptor = acceptor.clone();
+        let shutdown = shutdown.clone();
+
+        connections.spawn(async move {
+            let Some(acceptor) = acceptor else {
+                return serve_connection(stream, router, shutdown).await;
+            };
+
+            // Failed handshakes are already logged and counted by the acceptor
+            if let Ok((stream, ())) = acceptor.accept(stream, ()).await {
+                serve_connection(stream, router, shutdown).await;
+            }
+        });
+    }
+
+    let drain_timeout = shutdown.borrow().unwrap_or_default();
+    let drained = tokio::time::timeout(drain_timeout, async { while connections.join_next().await.is_some() {} }).await;
+
+    if drained.is_err() {
+        tracing::warn!(
+            connections = connections.len(),
+            "drain timeout elapsed, closing the remaining connections"
+        );
+    }
+    connections.shutdown().await;
+
+    Ok(())
+}
+
+async fn serve_connection<S: AsyncRead + AsyncWrite + Unpin + Send + 'static>(
+    stream: S,
+    router: Router,
+    mut shutdown: watch::Receiver<Option<Duration>>,
+) {
+    let builder = auto::Builder::new(TokioExecutor::new());
+    let connection = builder.serve_connection_with_upgrades(TokioIo::new(stream), TowerToHyperService::new(router));
+    tokio::pin!(connection);
+
+    let result = tokio::select! {
+        result = connection.as_mut() => result,
+        _ = shutdown.changed() => {
+            // Finish the in-flight requests, then close the connection
+            connection.as_mut().graceful_shutdown();
+            connection.await
+        }
+    };
+
+    if let Err(e) = result {
+        tracing::debug!(error = %e, "unix socket connection closed with an error");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn inherited_stream_sockets_are_used_by_address_family() {
+        let tcp_listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = tcp_listener.local_addr().unwrap();
+        let socket = inherited_socket(OwnedFd::from(tcp_listener)).unwrap();
+        assert_eq!(socket.listen_address(), Some(ListenAddress::Tcp(address)));
+
+        let path = std::env::temp_dir().join(format!("azure_app_exporter_test_{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let unix_listener = UnixListener::bind(&path).unwrap();
+        let socket = inherited_socket(OwnedFd::from(unix_listener)).unwrap();
+        assert_eq!(socket.listen_address(), Some(ListenAddress::Unix(path.clone())));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn inherited_datagram_sockets_are_rejected() {
+        let udp_socket = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let error = inherited_socket(OwnedFd::from(udp_socket)).err().unwrap();
+        assert!(error.contains("not a stream socket"), "{error}");
+    }
+
+    #[test]
+    fn unix_sockets_are_moved_into_place_with_their_mode() {
+        let dir = std::env::temp_dir().join(format!("azure_app_exporter_test_bind_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("exporter.sock");
+
+        // A stale socket is replaced, and the socket is bound again with another mode
+        for socket_mode in [0o660, 0o600] {
+            let _listener = bind_unix(&path, Some(socket_mode)).unwrap();
+
+            let metadata = std::fs::symlink_metadata(&path).unwrap();
+            assert!(metadata.file_type().is_socket());
+            assert_eq!(metadata.permissions().mode() & 0o777, socket_mode);
+            assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 1, "the private directory is removed");
+        }
+
+        std::fs::remove_file(&path).unwrap();
+        std::fs::write(&path, "not a socket").unwrap();
+        let error = bind_unix(&path, Some(0o660)).unwrap_err();
+        assert!(error.contains("the path exists and is not a socket"), "{error}");
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,264 @@
+/*
+ * Licensed to the Apache Software Foundation (ASF) under one
+ * or more contributor license agreements.  See the NOTICE file
+ * distributed with this work for additional information
+ * regarding copyright ownership.  The ASF licenses this file
+ * to you under the Apache License, Version 2.0 (the
+ * "License"); you may not use this file except in compliance
+ * with the License.  You may obtain a copy of the License at
+ *
+ *   http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing,
+ * software distributed under the License is distributed on an
+ * "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+ * KIND, either express or implied.  See the License for the
+ * specific language governing permissions and limitations
+ * under the License.
+ */
+
+use std::path::Path;
+
+use chrono::{DateTime, Utc};
+use ring::signature::{self, UnparsedPublicKey, VerificationAlgorithm};
+use rustls::{server::NoClientAuth, SignatureScheme};
+use rustls_pemfile::Item;
+use x509_parser::prelude::{FromDer, X509Certificate};
+
+use crate::{
+    settings::app_settings::{Listener, Settings, Tls},
+    utils::client_cert_verifier,
+};
+
+/// The certificate the server presents to its clients
+#[derive(Debug, Clone)]
+pub struct ServingCertificate {
+    pub subject: String,
+    pub not_after: DateTime<Utc>,
+}
+
+/// Build the TLS config of a listener from the settings, or `None` if it has no cert file and serves plain HTTP
+pub fn tls_config_from_settings(listener: &Listener, settings: &Settings) -> Option<Result<(rustls::ServerConfig, ServingCertificate), String>> {
+    let cert_path = listener.cert_file.as_deref()?;
+
+    Some(build_tls_config(
+        cert_path,
+        listener.key_file.as_deref(),
+        listener.cert_password.as_deref(),
+        listener.tls(settings),
+    ))
+}
+
+/// Build the TLS config of the server. This is done at startup, on every settings reload and when the cert or key file changes,
+/// so errors are returned instead of panicking to keep serving with the previous config.
+///
+/// The certificate chain and key are read from PEM files, where the key can also be in the cert file, or from a PKCS#12 (PFX) file.
+///
+/// If we want to select which TLS ciphers and protocols we want to use, we'll have to build the TLS config a bit more manually
+pub fn build_tls_config(
+    cert_path: &Path,
+    key_path: Option<&Path>,
+    password: Option<&str>,
+    tls_settings: &Tls,
+) -> Result<(rustls::ServerConfig, ServingCertificate), String> {
+    let cert_contents = std::fs::read(cert_path).map_err(|e| format!("failed reading tls cert file {}: {e}", cert_path.display()))?;
+
+    let (tls_certs, tls_key) = if is_pem(&cert_contents) {
+        let key_contents = match key_path {
+            Some(key_path) => std::fs::read(key_path).map_err(|e| format!("failed reading tls key file {}: {e}", key_path.display()))?,
+            None => cert_contents.clone(),
+        };
+
+        load_pem(&cert_contents, &key_contents)
+    } else {
+        load_pkcs12(&cert_contents, password.unwrap_or_default())
+    }
+    .map_err(|e| format!("failed loading tls cert file {}: {e}", cert_path.display()))?;
+
+    let serving_certificate = validate_chain(&tls_certs, &tls_key)?;
+
+    let mut server_config = rustls::server::ServerConfig::builder()
+        .with_cipher_suites(&tls_settings.rustls_cipher_suites())
+        .with_kx_groups(&tls_settings.rustls_kx_groups())
+        .with_protocol_versions(&tls_settings.rustls_protocol_versions())
+        .map_err(|e| format!("invalid tls config, perhaps an invalid cipher suite and protocol version combo is configured: {e}"))?
+        .with_client_cert_verifier(client_cert_verifier(tls_settings)?.unwrap_or_else(NoClientAuth::boxed))
+        .with_single_cert(tls_certs, tls_key)
+        .map_err(|e| format!("invalid tls cert or key: {e}"))?;
+
+    // We have to set this ourselves since we're building the [`ServerConfig`] from scratch
+    server_config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+
+    Ok((server_config, serving_certificate))
+}
+
+/// PEM files can have text before the first PEM section, like the "Bag Attributes" that `openssl pkcs12` writes, so this looks for
+/// a PEM header anywhere in the file. Anything else is assumed to be PKCS#12
+pub fn is_pem(contents: &[u8]) -> bool {
+    contents.windows(10).any(|window| window == b"-----BEGIN")
+}
+
+fn load_pem(cert_contents: &[u8], key_contents: &[u8]) -> Result<(Vec<rustls::Certificate>, rustls::PrivateKey), String> {
+    let tls_certs = rustls_pemfile::certs(&mut &*cert_contents)
+        .map(|cert| cert.map(|cert| rustls::Certificate(cert.to_vec())))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| format!("invalid PEM: {e}"))?;
+
+    // PKCS#8, RSA (PKCS#1) and EC (SEC1) keys. The key is not necessarily the first PEM item, like when the certificate and key are in the same file
+    let tls_key = rustls_pemfile::read_all(&mut &*key_contents)
+        .find_map(|item| match item {
+            Ok(Item::Pkcs1Key(key)) => Some(Ok(key.secret_pkcs1_der().to_vec())),
+            Ok(Item::Pkcs8Key(key)) => Some(Ok(key.secret_pkcs8_der().to_vec())),
+            Ok(Item::Sec1Key(key)) => Some(Ok(key.secret_sec1_der().to_vec())),
+            Ok(_) => None,
+            Err(e) => Some(Err(format!("invalid PEM: {e}"))),
+        })
+        .ok_or("no private key found")??;
+
+    Ok((tls_certs, rustls::PrivateKey(tls_key)))
+}
+
+fn load_pkcs12(contents: &[u8], password: &str) -> Result<(Vec<rustls::Certificate>, rustls::PrivateKey), String> {
+    let keystore = p12_keystore::KeyStore::from_pkcs12(contents, password).map_err(|e| format!("invalid PKCS#12 file: {e}"))?;
+
+    let (_, key_chain) = keystore.private_key_chain().ok_or("no private key found in PKCS#12 file")?;
+    let tls_certs = key_chain.chain().iter().map(|cert| rustls::Certificate(cert.as_der().to_vec())).collect();
+
+    Ok((tls_certs, rustls::PrivateKey(key_chain.key().to_vec())))
+}
+
+/// Check that the key belongs to the first certificate, and that each certificate of the chain is issued by the next one.
+/// Otherwise, rustls happily serves a mismatched key or chain and only the handshakes of the clients fail
+fn validate_chain(tls_certs: &[rustls::Certificate], tls_key: &rustls::PrivateKey) -> Result<ServingCertificate, String> {
+    let certificates = tls_certs
+        .iter()
+        .map(|cert| X509Certificate::from_der(&cert.0).map(|(_, certificate)| certificate))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| format!("invalid tls certificate: {e}"))?;
+
+    // The first certificate is the server's own, the others are the chain of intermediate certificates
+    let certificate = certificates.first().ok_or("tls cert file has no certificate")?;
+
+    // Sign a message with the key and verify it with the public key of the certificate
+    let signer = rustls::sign::any_supported_type(tls_key)
+        .ok()
+        .and_then(|signing_key| {
+            signing_key.choose_scheme(&[
+                SignatureScheme::RSA_PKCS1_SHA256,
+                SignatureScheme::ECDSA_NISTP256_SHA256,
+                SignatureScheme::ECDSA_NISTP384_SHA384,
+                SignatureScheme::ED25519,
+            ])
+        })
+        .ok_or("unsupported tls key, expected an RSA, ECDSA (P-256 or P-384) or Ed25519 private key")?;
+
+    let algorithm: &'static dyn VerificationAlgorithm = match signer.scheme() {
+        SignatureScheme::RSA_PKCS1_SHA256 => &signature::RSA_PKCS1_2048_8192_SHA256,
+        SignatureScheme::ECDSA_NISTP256_SHA256 => &signature::ECDSA_P256_SHA256_ASN1,
+        SignatureScheme::ECDSA_NISTP384_SHA384 => &signature::ECDSA_P384_SHA384_ASN1,
+        _ => &signature::ED25519,
+    };
+
+    let message = b"tls key check";
+    let signature = signer.sign(message).map_err(|e| format!("failed signing with the tls key: {e}"))?;
+
+    UnparsedPublicKey::new(algorithm, &certificate.public_key().subject_public_key.data)
+        .verify(message, &signature)
+        .map_err(|_| format!("tls key does not belong to the tls certificate {}", certificate.subject()))?;
+
+    for (certificate, issuer) in certificates.iter().zip(certificates.iter().skip(1)) {
+        certificate.verify_signature(Some(issuer.public_key())).map_err(|e| {
+            format!(
+                "invalid tls certificate chain, {} is not issued by the next certificate {}: {e}",
+                certificate.subject(),
+                issuer.subject()
+            )
+        })?;
+    }
+
+    Ok(ServingCertificate {
+        subject: certificate.subject().to_string(),
+        not_after: DateTime::from_timestamp(certificate.validity().not_after.timestamp(), 0).unwrap_or_default(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn is_pem_finds_a_pem_header_anywhere() {
+        assert!(is_pem(b"-----BEGIN CERTIFICATE-----\nMIIB\n-----END CERTIFICATE-----\n"));
+        assert!(is_pem(
+            b"Bag Attributes\n    localKeyID: 01 00 00 00\nsubject=CN = test\n-----BEGIN CERTIFICATE-----\nMIIB\n-----END CERTIFICATE-----\n"
+        ));
+        assert!(!is_pem(&[0x30, 0x82, 0x0a, 0x2d, 0x02, 0x01, 0x03]));
+        assert!(!is_pem(b""));
+    }
+
+    // Test certificates signed by testdata/ca.pem, generated with openssl and valid until 2125
+    const CA: &[u8] = include_bytes!("testdata/ca.pem");
+    const OTHER_CA: &[u8] = include_bytes!("testdata/other_ca.pem");
+    const RSA_CERT: &[u8] = include_bytes!("testdata/rsa.pem");
+    const RSA_PKCS1_KEY: &[u8] = include_bytes!("testdata/rsa_pkcs1.key");
+    const RSA_PKCS8_KEY: &[u8] = include_bytes!("testdata/rsa_pkcs8.key");
+    const EC_CERT: &[u8] = include_bytes!("testdata/ec.pem");
+    const EC_SEC1_KEY: &[u8] = include_bytes!("testdata/ec_sec1.key");
+    const RSA_PKCS12: &[u8] = include_bytes!("testdata/rsa.p12");
+
+    fn load_and_validate_pem(cert_contents: &[u8], key_contents: &[u8]) -> Result<ServingCertificate, String> {
+        let (tls_certs, tls_key) = load_pem(cert_contents, key_contents)?;
+        validate_chain(&tls_certs, &tls_key)
+    }
+
+    #[test]
+    fn pem_keys_in_each_format_are_loaded() {
+        for (cert, key, subject) in [
+            (RSA_CERT, RSA_PKCS1_KEY, "CN=rsa.example.com"),
+            (RSA_CERT, RSA_PKCS8_KEY, "CN=rsa.example.com"),
+            (EC_CERT, EC_SEC1_KEY, "CN=ec.example.com"),
+        ] {
+            let chain = [cert, CA].concat();
+            assert_eq!(load_and_validate_pem(&chain, key).unwrap().subject, subject);
+
+            // The key can also be in the cert file, after the certificates
+            assert_eq!(
+                load_and_validate_pem(&[&chain, key].concat(), &[&chain, key].concat()).unwrap().subject,
+                subject
+            );
+        }
+    }
+
+    #[test]
+    fn pem_without_key_is_rejected() {
+        assert_eq!(load_pem(RSA_CERT, CA).unwrap_err(), "no private key found");
+    }
+
+    #[test]
+    fn mismatched_key_is_rejected() {
+        let error = load_and_validate_pem(RSA_CERT, EC_SEC1_KEY).unwrap_err();
+        assert!(
+            error.starts_with("tls key does not belong to the tls certificate CN=rsa.example.com"),
+            "{error}"
+        );
+    }
+
+    #[test]
+    fn broken_chain_is_rejected() {
+        let error = load_and_validate_pem(&[RSA_CERT, OTHER_CA].concat(), RSA_PKCS8_KEY).unwrap_err();
+        assert!(
+            error.starts_with("invalid tls certificate chain, CN=rsa.example.com is not issued by the next certificate CN=Other CA"),
+            "{error}"
+        );
+    }
+
+    #[test]
+    fn pkcs12_is_loaded_with_its_password() {
+        let (tls_certs, tls_key) = load_pkcs12(RSA_PKCS12, "secret").unwrap();
+        assert_eq!(tls_certs.len(), 2);
+        assert_eq!(validate_chain(&tls_certs, &tls_key).unwrap().subject, "CN=rsa.example.com");
+
+        let error = load_pkcs12(RSA_PKCS12, "wrong").unwrap_err();
+        assert!(error.starts_with("invalid PKCS#12 file"), "{error}");
+    }
+}